@@ -1,18 +1,27 @@
 use std::{
-    collections::HashMap,
-    error::Error,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::{Seek, Write},
-    os::fd::AsRawFd,
-    sync::{Arc, Mutex},
+    io::BufReader,
+    ops::RangeInclusive,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use image::{DynamicImage, GenericImage, GenericImageView};
 use log::{debug, error, info, warn};
+use rayon::prelude::*;
 use wayland_client::{
     protocol::{wl_buffer, wl_compositor, wl_output, wl_registry, wl_shm, wl_shm_pool, wl_surface},
-    Connection, Dispatch, QueueHandle, WEnum,
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
 };
 use wayland_protocols_wlr::layer_shell::v1::client::{
     zwlr_layer_shell_v1::{Layer, ZwlrLayerShellV1},
@@ -20,28 +29,94 @@ use wayland_protocols_wlr::layer_shell::v1::client::{
 };
 
 use waypaper::{
-    config::{self, Config},
-    AppEvent,
+    cache::DiskCache,
+    config::{self, Config, OutputIdentity, OutputPreferences},
+    ipc::{self, DaemonStatus, Message, OutputStatus, Request, Response},
+    render::{self, Geometry, Renderer, RESIZE_FILTER},
+    slideshow::{self, Slideshow},
+    AppEvent, Error, Result,
 };
 
-fn main() {
+/// How often slideshows are checked for due image changes.
+const SLIDESHOW_TICK: Duration = Duration::from_secs(1);
+
+/// Exit status when the compositor lacks a global the daemon can't work without.
+const EXIT_UNSUPPORTED_COMPOSITOR: u8 = 3;
+
+// Versions of each global the daemon can use. Newer ones are bound at the highest version
+// listed here, as features the daemon doesn't know about can't be used anyway.
+const COMPOSITOR_VERSIONS: RangeInclusive<u32> = 4..=4;
+const SHM_VERSIONS: RangeInclusive<u32> = 1..=1;
+const LAYER_SHELL_VERSIONS: RangeInclusive<u32> = 1..=4;
+/// Names and descriptions are only sent from version 4 on, older outputs work without them.
+const OUTPUT_VERSION: u32 = 4;
+
+fn main() -> ExitCode {
     env_logger::init();
 
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e @ Error::MissingGlobals(_)) => {
+            error!("{}", e);
+            error!(
+                "waypaper needs a compositor with wlr-layer-shell support, like Sway or Hyprland"
+            );
+            ExitCode::from(EXIT_UNSUPPORTED_COMPOSITOR)
+        }
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<()> {
     let config: Config = Config::search();
-    let con = Connection::connect_to_env().unwrap();
+    let con = Connection::connect_to_env()?;
     let mut event_queue = con.new_event_queue();
     let qhandle = event_queue.handle();
     let display = con.display();
     display.get_registry(&qhandle, ());
-    let (_watcher, rx, tx) = config.watch();
-    let state = Arc::new(Mutex::new(State::new(config)));
+    let (rx, tx) = config.watch();
+    let state = Arc::new(Mutex::new(State::new(config, tx.clone(), con.clone())));
+
+    // All globals are announced by the time the first roundtrip completes
+    let mut dispatcher = Dispatcher {
+        state: Arc::clone(&state),
+    };
+    event_queue.roundtrip(&mut dispatcher)?;
+    let missing = state.lock().unwrap().globals.missing();
+    if !missing.is_empty() {
+        return Err(Error::MissingGlobals(missing));
+    }
     let sender = tx.clone();
     thread::spawn({
-        let mut signals = signal_hook::iterator::Signals::new(&[libc::SIGUSR1]).unwrap();
+        let mut signals = signal_hook::iterator::Signals::new([libc::SIGUSR1])?;
         move || {
             for _signal in signals.forever() {
                 info!("Received SIGUSR1, reloading config");
-                sender.send(AppEvent::ConfigChanged).unwrap();
+                if sender.send(AppEvent::ConfigChanged).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    thread::spawn({
+        let sender = tx.clone();
+        move || loop {
+            thread::sleep(SLIDESHOW_TICK);
+            if sender.send(AppEvent::SlideshowTick).is_err() {
+                break;
+            }
+        }
+    });
+
+    thread::spawn({
+        let sender = tx.clone();
+        move || {
+            if let Err(e) = serve_ipc(sender) {
+                error!("IPC server stopped: {}", e);
             }
         }
     });
@@ -51,7 +126,15 @@ fn main() {
         move || loop {
             match rx.recv() {
                 Ok(event) => {
-                    state.lock().unwrap().handle(event).unwrap();
+                    let result = state.lock().unwrap().handle(event);
+                    match result {
+                        Ok(followup) => {
+                            if let Err(e) = followup.run(&state) {
+                                error!("Error redrawing: {}", e);
+                            }
+                        }
+                        Err(e) => error!("Error handling event: {}", e),
+                    }
                 }
                 Err(e) => {
                     error!("Error receiving config event: {}", e);
@@ -60,16 +143,100 @@ fn main() {
         }
     });
 
-    thread::spawn({
-        let mut dispatcher = Dispatcher {
-            state: Arc::clone(&state),
-        };
-        move || loop {
-            event_queue.blocking_dispatch(&mut dispatcher).unwrap();
+    loop {
+        event_queue.blocking_dispatch(&mut dispatcher)?;
+    }
+}
+
+fn serve_ipc(sender: Sender<AppEvent>) -> std::io::Result<()> {
+    let Some(path) = ipc::socket_path() else {
+        warn!("XDG_RUNTIME_DIR is not set, not listening for IPC requests");
+        return Ok(());
+    };
+
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("Another daemon is listening on {}", path.display()),
+            ));
+        }
+        info!("Removing stale socket: {}", path.display());
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    info!("Listening for IPC requests on {}", path.display());
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_ipc_client(stream, sender) {
+                        warn!("Error handling IPC client: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Error accepting IPC connection: {}", e);
+            }
         }
-    })
-    .join()
-    .unwrap();
+    }
+
+    Ok(())
+}
+
+fn handle_ipc_client(stream: UnixStream, sender: Sender<AppEvent>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    while let Some(message) = ipc::read_message::<Request>(&mut reader)? {
+        debug!("IPC request: {:?}", message);
+
+        let response = if message.version != ipc::PROTOCOL_VERSION {
+            Response::Error(format!(
+                "Unsupported protocol version {} (daemon speaks {})",
+                message.version,
+                ipc::PROTOCOL_VERSION
+            ))
+        } else {
+            let (reply, response) = mpsc::channel();
+            sender.send(request_event(message.body, reply)).ok();
+            response
+                .recv()
+                .unwrap_or_else(|_| Response::Error("The daemon is shutting down".to_string()))
+        };
+
+        ipc::write_message(&mut writer, &Message::new(response))?;
+    }
+
+    Ok(())
+}
+
+fn request_event(request: Request, reply: Sender<Response>) -> AppEvent {
+    match request {
+        Request::SetBackground {
+            output,
+            background,
+            mode,
+        } => AppEvent::SetBackground {
+            output,
+            background,
+            mode,
+            reply,
+        },
+        Request::SetMode { output, mode } => AppEvent::SetMode {
+            output,
+            mode,
+            reply,
+        },
+        Request::Clear { output } => AppEvent::ClearOverrides { output, reply },
+        Request::Reload => AppEvent::Reload { reply },
+        Request::Redraw => AppEvent::Redraw { reply },
+        Request::ListOutputs => AppEvent::ListOutputs { reply },
+        Request::Query { output } => AppEvent::Query { output, reply },
+    }
 }
 
 struct Dispatcher {
@@ -81,6 +248,134 @@ struct Globals {
     compositor: Option<wl_compositor::WlCompositor>,
     shm: Option<wl_shm::WlShm>,
     layer_shell: Option<ZwlrLayerShellV1>,
+    /// Globals that were offered in a version older than the daemon needs.
+    outdated: Vec<(&'static str, u32)>,
+}
+
+impl Globals {
+    /// Binds a global at the newest version both sides support, unless the compositor only
+    /// has a version older than `versions`.
+    fn bind<I>(
+        &mut self,
+        registry: &wl_registry::WlRegistry,
+        name: u32,
+        version: u32,
+        versions: RangeInclusive<u32>,
+        qh: &QueueHandle<Dispatcher>,
+    ) -> Option<I>
+    where
+        I: Proxy + 'static,
+        Dispatcher: Dispatch<I, ()>,
+    {
+        let interface = I::interface().name;
+        if version < *versions.start() {
+            warn!(
+                "{} version {} is too old, need at least {}",
+                interface,
+                version,
+                versions.start()
+            );
+            self.outdated.push((interface, version));
+            return None;
+        }
+        Some(registry.bind(name, version.min(*versions.end()), qh, ()))
+    }
+
+    /// Describes the globals the daemon can't work without that are unavailable.
+    fn missing(&self) -> Vec<String> {
+        let required = [
+            ("wl_compositor", self.compositor.is_some()),
+            ("wl_shm", self.shm.is_some()),
+            ("zwlr_layer_shell_v1", self.layer_shell.is_some()),
+        ];
+        required
+            .into_iter()
+            .filter(|(_, bound)| !bound)
+            .map(
+                |(interface, _)| match self.outdated.iter().find(|(name, _)| *name == interface) {
+                    Some((_, version)) => format!("{} (version {} is too old)", interface, version),
+                    None => interface.to_string(),
+                },
+            )
+            .collect()
+    }
+}
+
+/// Number of buffers per output, so a new frame never overwrites the one on screen.
+const BUFFER_SLOTS: usize = 2;
+
+/// One of the buffers an output alternates between.
+#[derive(Debug)]
+struct BufferSlot {
+    buffer: wl_buffer::WlBuffer,
+    /// Offset into the buffer file.
+    offset: usize,
+    /// Length in bytes.
+    len: usize,
+    /// Size and format the buffer was created with.
+    width: usize,
+    height: usize,
+    format: wl_shm::Format,
+    /// Attached to the surface and not released by the compositor yet.
+    busy: bool,
+}
+
+impl BufferSlot {
+    /// Whether frames for `output` in `format` fit this buffer.
+    fn fits(&self, output: &Output, format: wl_shm::Format) -> bool {
+        self.width == output.width && self.height == output.height && self.format == format
+    }
+}
+
+/// The buffer file mapped into memory, so frames are copied straight into the shm pool.
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: The mapping is owned by one `State` and only handed out through `&mut self`
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> std::io::Result<Mapping> {
+        // SAFETY: A new shared mapping, nothing else refers to this memory yet
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    /// `len` bytes starting at `offset`, or `None` if they don't fit into the mapping.
+    fn slice_mut(&mut self, offset: usize, len: usize) -> Option<&mut [u8]> {
+        if offset.checked_add(len)? > self.len {
+            return None;
+        }
+        // SAFETY: The range lies within the mapping, which lives as long as `self`
+        Some(unsafe { std::slice::from_raw_parts_mut(self.ptr.add(offset), len) })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` describe a mapping created in `Mapping::new`
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -90,276 +385,1164 @@ struct State {
     shm_pool: Option<wl_shm_pool::WlShmPool>,
     shm_formats: Vec<wl_shm::Format>,
     buffer_file: Option<File>,
+    /// All of `buffer_file`, remapped whenever the file grows.
+    mapping: Option<Mapping>,
     max_buffer_size: usize,
-    buffers: HashMap<String, wl_buffer::WlBuffer>,
+    buffers: HashMap<String, Vec<BufferSlot>>,
+    /// Replaced buffers the compositor still shows, their memory is reused once released.
+    retired_buffers: Vec<BufferSlot>,
     surfaces: HashMap<String, wl_surface::WlSurface>,
-    surface_configured: u32,
+    layer_surfaces: HashMap<String, ZwlrLayerSurfaceV1>,
+    configured_surfaces: HashSet<String>,
     outputs: Vec<Output>,
-    output_builders: Vec<OutputBuilder>,
-    total_pixels: usize,
+    /// Output properties as last announced by the compositor, by registry name.
+    output_builders: HashMap<u32, OutputBuilder>,
+    /// Preferences set over IPC, which take precedence over the config file.
+    overrides: HashMap<String, OutputPreferences>,
+    slideshows: HashMap<String, Slideshow>,
+    /// Rendered images in the shm pixel format, reused while they stay on screen.
+    frame_cache: HashMap<ImageKey, Vec<u8>>,
+    /// Frames the disk cache seemed to have but couldn't be read, rendered on the pool instead.
+    disk_misses: HashSet<ImageKey>,
+    /// What each output currently shows. Outputs are left out after their buffers are
+    /// recreated or their scale changes, so they are drawn again.
+    drawn: HashMap<String, FrameSpec>,
+    /// A frame was held back because all buffers of its output were still in use.
+    waiting_for_release: bool,
+    disk_cache: Option<DiskCache>,
+    /// Used to hand work over to the event thread.
+    events: Option<Sender<AppEvent>>,
+    /// Flushed after presenting, since redraws also happen outside of event dispatching.
+    connection: Option<Connection>,
 }
 
 impl State {
-    fn new(config: config::Config) -> State {
+    fn new(config: config::Config, events: Sender<AppEvent>, connection: Connection) -> State {
         State {
             config,
+            events: Some(events),
+            connection: Some(connection),
+            disk_cache: DiskCache::open_default(),
             ..Default::default()
         }
     }
 
-    fn setup_buffer_file(&mut self, qh: &QueueHandle<Dispatcher>) {
-        let file = if self.buffer_file.is_some() {
-            self.buffer_file.as_ref().unwrap().try_clone().unwrap()
-        } else {
-            info!("Creating tempfile");
-            tempfile::tempfile().expect("Error creating tempfile")
-        };
+    /// The most compact format we can write that the compositor supports.
+    fn pixel_format(&self) -> wl_shm::Format {
+        render::PREFERRED_FORMATS
+            .into_iter()
+            .find(|format| self.shm_formats.contains(format))
+            // Every compositor has to support this one
+            .unwrap_or(wl_shm::Format::Xrgb8888)
+    }
 
-        info!("Resizing tempfile");
-        debug!(
-            "New buffer size: {}",
-            std::cmp::max(self.max_buffer_size, self.total_pixels * 3)
-        );
+    fn bytes_per_pixel(&self) -> usize {
+        render::bytes_per_pixel(self.pixel_format())
+    }
 
-        file.set_len(std::cmp::max(self.max_buffer_size, self.total_pixels * 3) as u64)
-            .expect("Error resizing tempfile");
+    /// The lowest offset where `len` bytes fit without touching any buffer in use, including
+    /// `pending` ones that are about to be created.
+    fn free_offset(&self, len: usize, pending: &[BufferSlot]) -> usize {
+        let mut taken: Vec<(usize, usize)> = self
+            .buffers
+            .values()
+            .flatten()
+            .chain(&self.retired_buffers)
+            .chain(pending)
+            .map(|slot| (slot.offset, slot.offset + slot.len))
+            .collect();
+        taken.sort_unstable();
 
-        self.buffer_file = Some(file);
+        let mut offset = 0;
+        for (start, end) in taken {
+            if offset + len <= start {
+                break;
+            }
+            offset = offset.max(end);
+        }
+        offset
+    }
 
+    /// Makes sure the buffer file and shm pool hold at least `size` bytes.
+    fn grow_buffer_file(
+        &mut self,
+        size: usize,
+        qh: &QueueHandle<Dispatcher>,
+    ) -> Result<wl_shm_pool::WlShmPool> {
         if let Some(shm_pool) = &self.shm_pool {
-            info!("Resizing shm pool");
-            shm_pool.resize((self.total_pixels * 3) as i32);
-            self.max_buffer_size = std::cmp::max(self.max_buffer_size, self.total_pixels * 3);
-        } else {
-            info!("Creating shm pool");
-            self.shm_pool = Some(self.globals.shm.as_ref().unwrap().create_pool(
-                self.buffer_file.as_ref().unwrap().as_raw_fd(),
-                (self.total_pixels * 3) as i32,
-                qh,
-                (),
-            ));
+            if size <= self.max_buffer_size {
+                return Ok(shm_pool.clone());
+            }
         }
+
+        let shm = self
+            .globals
+            .shm
+            .as_ref()
+            .ok_or_else(|| Error::MissingGlobals(vec!["wl_shm".to_string()]))?;
+
+        info!("Growing buffer file");
+        debug!("New buffer size: {}", size);
+
+        let file = match &mut self.buffer_file {
+            Some(file) => file,
+            empty => empty.insert(create_buffer_file()?),
+        };
+        file.set_len(size as u64)?;
+        self.mapping = Some(Mapping::new(file, size)?);
+        self.max_buffer_size = size;
+
+        // Pools can only grow, so space left behind by removed outputs is reused for later buffers
+        let shm_pool = match &self.shm_pool {
+            Some(shm_pool) => {
+                info!("Resizing shm pool");
+                shm_pool.resize(size as i32);
+                shm_pool.clone()
+            }
+            None => {
+                info!("Creating shm pool");
+                self.shm_pool
+                    .insert(shm.create_pool(file.as_raw_fd(), size as i32, qh, ()))
+                    .clone()
+            }
+        };
+        Ok(shm_pool)
     }
 
     fn setup_buffers(&mut self, qh: &QueueHandle<Dispatcher>) {
-        let mut offset = 0;
+        debug!("Using pixel format: {:?}", self.pixel_format());
+
         for output in self.outputs.clone() {
-            self.setup_buffer(&output, offset, qh);
-            offset += output.pixel_count * 3;
+            let format = self.pixel_format();
+            let fits = self
+                .buffers
+                .get(&output.name)
+                .is_some_and(|slots| slots.iter().all(|slot| slot.fits(&output, format)));
+            if fits {
+                debug!("{}: Buffers still fit", output.name);
+            } else if let Err(e) = self.setup_buffer(&output, qh) {
+                error!("{}: Error creating buffers: {}", output.name, e);
+            }
+
+            // The buffer holds pixels in the orientation and resolution of the output hardware.
+            // Both take effect with the commit that attaches the next frame.
+            if let Some(surface) = self.surfaces.get(&output.name) {
+                surface.set_buffer_scale(output.scale);
+                surface.set_buffer_transform(output.transform);
+            }
         }
 
+        self.waiting_for_release = false;
+
         info!("Done setting up buffers. Redrawing.");
-        self.draw_all().expect("Error drawing to file");
+        self.request_redraw();
     }
 
-    fn setup_buffer(&mut self, output: &Output, offset: usize, qh: &QueueHandle<Dispatcher>) {
-        if self.buffers.contains_key(&output.name) {
-            info!("Buffer for output {} already exists", output.name);
+    /// Replaces the buffers of an output whose size or format changed.
+    fn setup_buffer(&mut self, output: &Output, qh: &QueueHandle<Dispatcher>) -> Result<()> {
+        if let Some(slots) = self.buffers.remove(&output.name) {
+            info!("Replacing buffers for output {}", output.name);
 
-            self.buffers.get(&output.name).unwrap().destroy();
+            // The compositor may still be showing one, its memory stays untouched until released
+            for slot in slots {
+                if slot.busy {
+                    self.retired_buffers.push(slot);
+                } else {
+                    slot.buffer.destroy();
+                }
+            }
         }
+        // The new buffers are empty, so the output has to be drawn again
+        self.drawn.remove(&output.name);
 
-        info!("Creating buffer for output: {}", output.name);
-        debug!(
-            "Buffer size: {}, offset: {}",
-            output.pixel_count * 3,
-            offset
-        );
+        info!("Creating buffers for output: {}", output.name);
+        let format = self.pixel_format();
+        let len = output.pixel_count * self.bytes_per_pixel();
+
+        let mut slots = Vec::with_capacity(BUFFER_SLOTS);
+        for _ in 0..BUFFER_SLOTS {
+            let offset = self.free_offset(len, &slots);
+            debug!("Buffer size: {}, offset: {}", len, offset);
+
+            let shm_pool = self.grow_buffer_file(offset + len, qh)?;
+            slots.push(BufferSlot {
+                buffer: shm_pool.create_buffer(
+                    offset as i32,
+                    output.width as i32,
+                    output.height as i32,
+                    (output.width * self.bytes_per_pixel()) as i32,
+                    format,
+                    qh,
+                    (),
+                ),
+                offset,
+                len,
+                width: output.width,
+                height: output.height,
+                format,
+                busy: false,
+            });
+        }
 
-        let buffer = self.shm_pool.as_ref().unwrap().create_buffer(
-            offset as i32,
-            output.width as i32,
-            output.height as i32,
-            output.width as i32 * 3,
-            wl_shm::Format::Bgr888,
+        self.buffers.insert(output.name.to_string(), slots);
+        Ok(())
+    }
+
+    /// Creates the layer surface that shows the wallpaper on a new output.
+    fn add_output(&mut self, output: Output, qh: &QueueHandle<Dispatcher>) -> Result<()> {
+        let compositor = self
+            .globals
+            .compositor
+            .as_ref()
+            .ok_or_else(|| Error::MissingGlobals(vec!["wl_compositor".to_string()]))?;
+        let layer_shell = self
+            .globals
+            .layer_shell
+            .as_ref()
+            .ok_or_else(|| Error::MissingGlobals(vec!["zwlr_layer_shell_v1".to_string()]))?;
+
+        let surface = compositor.create_surface(qh, ());
+        let layer_surface = layer_shell.get_layer_surface(
+            &surface,
+            output.wl_output.as_ref(),
+            Layer::Background,
+            String::from("waypaper"),
             qh,
-            (),
+            output.name.clone(),
         );
-        let surface = self.surfaces.get(&output.name).unwrap();
 
-        surface.attach(Some(&buffer), 0, 0);
-        surface.damage_buffer(0, 0, i32::MAX, i32::MAX);
+        let (width, height) = output.logical_size();
+        layer_surface.set_size(width, height);
+        layer_surface.set_exclusive_zone(-1);
+
         surface.commit();
+        self.surfaces.insert(output.name.clone(), surface);
+        self.layer_surfaces
+            .insert(output.name.clone(), layer_surface);
+        self.outputs.push(output);
+        Ok(())
+    }
+
+    /// Tear down everything belonging to an output whose global was removed.
+    fn remove_output(&mut self, global_name: u32) {
+        self.output_builders.remove(&global_name);
+
+        let Some(index) = self
+            .outputs
+            .iter()
+            .position(|output| output.global_name == global_name)
+        else {
+            return;
+        };
+
+        let output = self.outputs.remove(index);
+        info!("Output {} removed", output.name);
 
-        self.buffers.insert(output.name.to_string(), buffer);
+        self.configured_surfaces.remove(&output.name);
+        self.drawn.remove(&output.name);
+
+        if let Some(layer_surface) = self.layer_surfaces.remove(&output.name) {
+            layer_surface.destroy();
+        }
+        if let Some(surface) = self.surfaces.remove(&output.name) {
+            surface.destroy();
+        }
+        // Their space in the pool is reused by buffers created later
+        for slot in self.buffers.remove(&output.name).into_iter().flatten() {
+            slot.buffer.destroy();
+        }
+        if let Some(wl_output) = output.wl_output {
+            if wl_output.version() >= 3 {
+                wl_output.release();
+            }
+        }
     }
 
-    fn next_output_id(&self) -> usize {
-        self.output_builders.len()
+    fn all_surfaces_configured(&self) -> bool {
+        self.surfaces
+            .keys()
+            .all(|name| self.configured_surfaces.contains(name))
     }
 
-    fn draw_all(&self) -> Result<(), Box<dyn Error>> {
-        info!("Writing to file");
+    /// Decides what each output should show.
+    fn planned_frames(&mut self) -> Vec<(Output, OutputPreferences, FrameSpec)> {
+        self.sync_slideshows();
 
-        debug!("Total pixels: {}", self.total_pixels);
+        let format = self.pixel_format();
+        let mut frames = Vec::new();
+        for output in self.outputs.iter() {
+            let prefs = self.preferences(output).unwrap_or_default();
+            let spec = self.frame_spec(output, &prefs, format);
+            frames.push((output.clone(), prefs, spec));
+        }
+        frames
+    }
 
-        let mut buf = std::io::BufWriter::new(self.buffer_file.as_ref().unwrap());
-        buf.seek(std::io::SeekFrom::Start(0))?;
+    fn is_drawn(&self, output: &Output, spec: &FrameSpec) -> bool {
+        self.drawn.get(&output.name) == Some(spec)
+    }
 
-        // used to check if the buffer position is correct
-        let mut buf_pos = 0;
+    /// Collects the frames that need to be rendered before the next [`State::present`].
+    fn render_jobs(&mut self) -> Vec<RenderJob> {
+        let mut jobs: Vec<RenderJob> = Vec::new();
 
-        let output_preferences = self.config.output_preferences.as_ref().unwrap();
+        for (output, prefs, spec) in self.planned_frames() {
+            if self.is_drawn(&output, &spec) || jobs.iter().any(|job| job.spec == spec) {
+                continue;
+            }
+            // Solid colors are filled in place while presenting, cached frames are copied
+            let ready = match &spec {
+                FrameSpec::Image(key) => {
+                    self.frame_cache.contains_key(key)
+                        || (!self.disk_misses.contains(key) && self.is_on_disk(key, &output))
+                }
+                FrameSpec::Color { .. } => true,
+                FrameSpec::Gradient { .. } => false,
+            };
+            if ready {
+                continue;
+            }
 
-        for output in self.outputs.iter() {
-            if output_preferences.contains_key(&output.name)
-                && output_preferences[&output.name].background.is_some()
+            jobs.push(RenderJob {
+                output,
+                prefs,
+                spec,
+                disk_cache: self.disk_cache.clone(),
+            });
+        }
+
+        jobs
+    }
+
+    /// Whether the disk cache has the frame for `key`, which is then read straight into the
+    /// buffer while presenting.
+    fn is_on_disk(&self, key: &ImageKey, output: &Output) -> bool {
+        let Some(disk_cache) = &self.disk_cache else {
+            return false;
+        };
+        let len = output.renderer(key.format).frame_len();
+        // Sources that weren't hashed yet are left to the render pool, which checks the disk
+        // before rendering
+        key.known_disk_key(disk_cache)
+            .is_some_and(|disk_key| disk_cache.contains(&disk_key, len))
+    }
+
+    /// Writes every output whose frame changed since it was last drawn.
+    ///
+    /// Each frame goes into a buffer the compositor is not reading from. Frames missing from
+    /// `rendered` and the caches are left to the next redraw. Outputs that can't be drawn keep
+    /// showing their previous frame, slideshows move on to their next image. Returns the
+    /// names of the outputs that were repainted, along with the buffer slot holding the new
+    /// frame.
+    fn draw_all(&mut self, rendered: Vec<(FrameSpec, Option<Vec<u8>>)>) -> Vec<(String, usize)> {
+        info!("Writing to buffers");
+
+        let format = self.pixel_format();
+        let frames = self.planned_frames();
+
+        // Keep images around for later redraws
+        let rendered: Vec<_> = rendered
+            .into_iter()
+            .filter_map(|(spec, frame)| match (spec, frame) {
+                (FrameSpec::Image(key), Some(frame)) => {
+                    self.disk_misses.remove(&key);
+                    self.frame_cache.insert(key, frame);
+                    None
+                }
+                rendered => Some(rendered),
+            })
+            .collect();
+
+        let mut repainted = Vec::new();
+
+        for (output, _, spec) in frames.iter() {
+            match self.draw_output(output, spec, format, &rendered) {
+                Ok(Some(index)) => repainted.push((output.name.clone(), index)),
+                Ok(None) => {}
+                Err(e) => error!("{}: {}", output.name, e),
+            }
+        }
+        info!("Done writing to buffers");
+        self.skip_broken_images(&rendered);
+
+        // Only keep frames that are still on screen
+        self.frame_cache.retain(|key, _| {
+            frames
+                .iter()
+                .any(|(_, _, spec)| matches!(spec, FrameSpec::Image(k) if k == key))
+        });
+
+        repainted
+    }
+
+    /// Writes the frame of one output into a free buffer and returns the slot it went into,
+    /// or `None` if nothing was drawn.
+    fn draw_output(
+        &mut self,
+        output: &Output,
+        spec: &FrameSpec,
+        format: wl_shm::Format,
+        rendered: &[(FrameSpec, Option<Vec<u8>>)],
+    ) -> Result<Option<usize>> {
+        if self.is_drawn(output, spec) {
+            debug!("{}: Unchanged, skipping", output.name);
+            return Ok(None);
+        }
+
+        let Some(slots) = self.buffers.get(&output.name) else {
+            debug!("{}: No buffers yet, skipping", output.name);
+            return Ok(None);
+        };
+        if !slots.iter().all(|slot| slot.fits(output, format)) {
+            // Drawn once the surface is configured and its buffers are replaced
+            debug!("{}: Buffers don't fit yet, skipping", output.name);
+            return Ok(None);
+        }
+        let Some(index) = slots.iter().position(|slot| !slot.busy) else {
+            // Drawn again once the compositor releases one of them
+            debug!("{}: All buffers busy, waiting for release", output.name);
+            self.waiting_for_release = true;
+            return Ok(None);
+        };
+        let offset = slots[index].offset;
+
+        if let FrameSpec::Color { color: None, .. } = spec {
+            warn!(
+                "{}: No background specified, defaulting to black",
+                output.name
+            );
+        }
+
+        // Frames that are neither in memory nor just rendered may be on disk
+        let disk_key = match (spec, &self.disk_cache) {
+            (FrameSpec::Image(key), Some(disk_cache))
+                if !self.frame_cache.contains_key(key)
+                    && !rendered.iter().any(|(rendered, _)| rendered == spec) =>
             {
-                let prefs = &output_preferences[&output.name];
-                let background = prefs.background.as_ref().unwrap();
-                info!("Loading image: {}", background.display());
-                let image = image::io::Reader::open(background)?
-                    .with_guessed_format()?
-                    .decode()?;
-
-                debug!("Image size: {:?}", image.dimensions());
-                info!("{}: Writing background image to buffer", output.name);
-
-                write_image(
-                    image,
-                    &prefs.mode,
-                    output.width as u32,
-                    output.height as u32,
-                    &mut buf,
-                )?;
-            } else {
-                warn!(
-                    "{}: No background image specified, defaulting to black",
-                    output.name
-                );
-
-                write_default_color(output, &mut buf)?;
-                info!("{}: Done writing to buffer", output.name);
-            }
-
-            buf_pos += output.pixel_count * 3;
-            debug!("Buffer position: {}", buf_pos);
-            if let Ok(pos) = buf.seek(std::io::SeekFrom::Current(0)) {
-                if pos as usize != buf_pos {
-                    warn!(
-                        "Buffer position mismatch (real: {}, expected: {})",
-                        pos, buf_pos
-                    );
+                key.known_disk_key(disk_cache)
+            }
+            _ => None,
+        };
+
+        let size = output.pixel_count * render::bytes_per_pixel(format);
+        let dst = self
+            .mapping
+            .as_mut()
+            .and_then(|mapping| mapping.slice_mut(offset, size))
+            .ok_or_else(|| std::io::Error::other("Buffer is outside the buffer file"))?;
+
+        info!("{}: Writing frame to buffer", output.name);
+        if let FrameSpec::Color { color, .. } = spec {
+            // Solid colors don't need a frame, they are filled in place
+            render::fill_color(dst, color.unwrap_or_default(), format);
+        } else if disk_key.as_ref().is_some_and(|disk_key| {
+            self.disk_cache
+                .as_ref()
+                .is_some_and(|disk_cache| disk_cache.read_into(disk_key, dst))
+        }) {
+            info!("{}: Loaded frame from disk cache", output.name);
+        } else {
+            let frame = match spec {
+                FrameSpec::Image(key) if self.frame_cache.contains_key(key) => {
+                    debug!("{}: Using cached frame", output.name);
+                    &self.frame_cache[key]
                 }
-            } else {
-                warn!("Error getting buffer position");
+                _ => match rendered.iter().find(|(rendered, _)| rendered == spec) {
+                    Some((_, Some(frame))) => frame,
+                    // Rendering failed, which was logged then
+                    Some((_, None)) => return Ok(None),
+                    None => {
+                        // Gone from the disk cache after all, or the outputs changed while
+                        // rendering. Either way it's rendered on the pool rather than here with
+                        // the state locked, and the output keeps its frame until then.
+                        debug!("{}: Frame missing, redrawing", output.name);
+                        if let (FrameSpec::Image(key), Some(_)) = (spec, &disk_key) {
+                            self.disk_misses.insert(key.clone());
+                        }
+                        self.request_redraw();
+                        return Ok(None);
+                    }
+                },
+            };
+
+            if frame.len() != size {
+                return Err(std::io::Error::other(format!(
+                    "Frame has {} bytes, expected {}",
+                    frame.len(),
+                    size
+                ))
+                .into());
             }
+            dst.copy_from_slice(frame);
+        }
+
+        self.drawn.insert(output.name.clone(), spec.clone());
+        Ok(Some(index))
+    }
+
+    /// Writes the frames to the buffer file and attaches the new buffers.
+    fn present(&mut self, rendered: Vec<(FrameSpec, Option<Vec<u8>>)>) -> Result<()> {
+        let repainted = self.draw_all(rendered);
+        debug!("Attaching buffers");
+        for (name, index) in repainted {
+            let (Some(surface), Some(slot)) = (
+                self.surfaces.get(&name),
+                self.buffers
+                    .get_mut(&name)
+                    .and_then(|slots| slots.get_mut(index)),
+            ) else {
+                continue;
+            };
+            surface.attach(Some(&slot.buffer), 0, 0);
+            surface.damage_buffer(0, 0, i32::MAX, i32::MAX);
+            surface.commit();
+            slot.busy = true;
         }
-        std::io::Write::flush(&mut buf).unwrap();
-        info!("Done writing to file");
+        debug!("Done attaching buffers");
 
+        // The dispatch thread only flushes once it wakes up for the next event
+        if let Some(connection) = &self.connection {
+            connection.flush()?;
+        }
         Ok(())
     }
 
-    fn handle(&mut self, event: AppEvent) -> Result<(), Box<dyn Error>> {
-        debug!("Handling event: {:?}", event);
+    /// The compositor is done reading from `buffer`, so it can be drawn into again.
+    fn release_buffer(&mut self, buffer: &wl_buffer::WlBuffer) {
+        if let Some(slot) = self
+            .buffers
+            .values_mut()
+            .flatten()
+            .find(|slot| &slot.buffer == buffer)
+        {
+            slot.busy = false;
+        }
+        if let Some(index) = self
+            .retired_buffers
+            .iter()
+            .position(|slot| &slot.buffer == buffer)
+        {
+            // Frees its memory for new buffers
+            self.retired_buffers.swap_remove(index).buffer.destroy();
+        }
+
+        if self.waiting_for_release {
+            self.waiting_for_release = false;
+            self.request_redraw();
+        }
+    }
 
-        match event {
+    /// Wayland events are handled with the state locked, so drawing is left to the event thread.
+    fn request_redraw(&self) {
+        if let Some(events) = &self.events {
+            events.send(AppEvent::OutputChanged).ok();
+        }
+    }
+
+    fn frame_spec(
+        &self,
+        output: &Output,
+        prefs: &OutputPreferences,
+        format: wl_shm::Format,
+    ) -> FrameSpec {
+        if let Some(path) = self.background_image(&output.name, prefs) {
+            FrameSpec::Image(ImageKey {
+                path: path.to_path_buf(),
+                modified: std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok(),
+                size: output.geometry().render_size(),
+                transform: output.transform,
+                format,
+                mode: prefs.mode,
+                pad_color: prefs.pad_color,
+                tile_scale: prefs.tile_scale.map(f32::to_bits),
+                tile_offset: prefs.tile_offset,
+            })
+        } else if let Some(gradient) = prefs.gradient {
+            FrameSpec::Gradient {
+                gradient,
+                size: output.geometry().render_size(),
+                transform: output.transform,
+                format,
+            }
+        } else {
+            FrameSpec::Color {
+                color: prefs.color,
+                pixel_count: output.pixel_count,
+                format,
+            }
+        }
+    }
+
+    fn preferences(&self, output: &Output) -> Option<OutputPreferences> {
+        match self.overrides.get(&output.name) {
+            Some(prefs) => Some(prefs.clone()),
+            None => self.config.preferences(&output.identity()),
+        }
+    }
+
+    /// Start slideshows for outputs that show a directory or pattern and stop stale ones.
+    ///
+    /// Slideshows keep their images, position and timer as long as their background stays
+    /// the same, so only a new background touches the filesystem.
+    fn sync_slideshows(&mut self) {
+        let mut slideshows = std::mem::take(&mut self.slideshows);
+
+        for output in self.outputs.iter() {
+            let Some(prefs) = self.preferences(output) else {
+                continue;
+            };
+            let Some(source) = prefs.background.as_ref() else {
+                continue;
+            };
+            let interval = prefs.interval.unwrap_or(slideshow::DEFAULT_INTERVAL);
+
+            let slideshow = match slideshows.remove(&output.name) {
+                Some(mut slideshow) if slideshow.source() == source => {
+                    slideshow.set_timing(interval, prefs.order);
+                    slideshow
+                }
+                _ if slideshow::is_collection(source) => {
+                    Slideshow::new(source, interval, prefs.order)
+                }
+                _ => continue,
+            };
+            self.slideshows.insert(output.name.clone(), slideshow);
+        }
+    }
+
+    /// Moves slideshows showing an image that failed to render on to their next image.
+    fn skip_broken_images(&mut self, rendered: &[(FrameSpec, Option<Vec<u8>>)]) {
+        let mut skipped = false;
+        for (spec, frame) in rendered {
+            let (FrameSpec::Image(key), None) = (spec, frame) else {
+                continue;
+            };
+            for slideshow in self.slideshows.values_mut() {
+                skipped |= slideshow.skip_broken(&key.path);
+            }
+        }
+
+        // Rendered like any other change, rather than here with the state locked
+        if skipped {
+            self.request_redraw();
+        }
+    }
+
+    /// The image currently shown on an output, taking slideshows into account.
+    fn background_image<'a>(
+        &'a self,
+        output_name: &str,
+        prefs: &'a OutputPreferences,
+    ) -> Option<&'a Path> {
+        match self.slideshows.get(output_name) {
+            Some(slideshow) => slideshow.current(),
+            None => prefs.background.as_deref(),
+        }
+    }
+
+    fn output(&self, name: &str) -> Result<&Output> {
+        self.outputs
+            .iter()
+            .find(|output| output.name == name)
+            .ok_or_else(|| Error::Request(format!("Unknown output '{}'", name)))
+    }
+
+    fn output_status(&self, output: &Output) -> OutputStatus {
+        let prefs = self.preferences(output).unwrap_or_default();
+        OutputStatus {
+            name: output.name.clone(),
+            description: output.description.clone(),
+            width: output.mode_size.0,
+            height: output.mode_size.1,
+            background: self
+                .background_image(&output.name, &prefs)
+                .map(Path::to_path_buf),
+            mode: prefs.mode,
+            overridden: self.overrides.contains_key(&output.name),
+        }
+    }
+
+    /// Start from the current preferences of an output so that unrelated settings are kept.
+    fn override_preferences(&mut self, output_name: &str) -> Result<&mut OutputPreferences> {
+        let prefs = self.preferences(self.output(output_name)?);
+        Ok(self
+            .overrides
+            .entry(output_name.to_string())
+            .or_insert_with(|| prefs.unwrap_or_default()))
+    }
+
+    /// Applies an event to the state, drawing is left to the returned [`Followup`].
+    fn handle(&mut self, event: AppEvent) -> Result<Followup> {
+        if !matches!(event, AppEvent::SlideshowTick) {
+            debug!("Handling event: {:?}", event);
+        }
+
+        let followup = match event {
             AppEvent::ConfigChanged => {
-                self.config.reload()?;
-                self.draw_all()?;
-                debug!("Damaging surfaces");
-                for s in self.surfaces.values() {
-                    s.damage_buffer(0, 0, i32::MAX, i32::MAX);
-                    s.commit();
+                // A broken config is logged and shown in the status, the old one stays in use
+                let reloaded = self.config.reload().is_ok();
+                Followup {
+                    redraw: reloaded,
+                    reply: None,
                 }
-                debug!("Done damaging surfaces");
             }
             AppEvent::OutputChanged => {
                 info!("Output changed, redrawing");
+                Followup::redraw()
+            }
+            AppEvent::SlideshowTick => {
+                let now = Instant::now();
+                let mut changed = false;
+                for (output_name, slideshow) in self.slideshows.iter_mut() {
+                    if slideshow.is_due(now) {
+                        info!("{}: Showing next slideshow image", output_name);
+                        slideshow.advance();
+                        changed = true;
+                    }
+                }
+                Followup {
+                    redraw: changed,
+                    reply: None,
+                }
+            }
+            AppEvent::SetBackground {
+                output,
+                background,
+                mode,
+                reply,
+            } => {
+                Followup::reply_after_redraw(reply, self.set_background(&output, background, mode))
+            }
+            AppEvent::SetMode {
+                output,
+                mode,
+                reply,
+            } => Followup::reply_after_redraw(reply, self.set_mode(&output, mode)),
+            AppEvent::ClearOverrides { output, reply } => {
+                match output {
+                    Some(output) => {
+                        self.overrides.remove(&output);
+                    }
+                    None => self.overrides.clear(),
+                }
+                Followup::reply_after_redraw(reply, Ok(Response::Ok))
             }
+            AppEvent::Reload { reply } => {
+                let response = self.config.reload().map(|_| Response::Ok);
+                Followup::reply_after_redraw(reply, response)
+            }
+            AppEvent::Redraw { reply } => {
+                // Repaint everything, even outputs that look unchanged
+                self.drawn.clear();
+                Followup::reply_after_redraw(reply, Ok(Response::Ok))
+            }
+            AppEvent::ListOutputs { reply } => {
+                let outputs = self
+                    .outputs
+                    .iter()
+                    .map(|output| self.output_status(output))
+                    .collect();
+                Followup::reply(reply, Response::Outputs(outputs))
+            }
+            AppEvent::Query { output, reply } => {
+                Followup::reply(reply, into_response(self.query(output.as_deref())))
+            }
+        };
+        Ok(followup)
+    }
+
+    fn set_background(
+        &mut self,
+        output_name: &str,
+        background: PathBuf,
+        mode: Option<config::Mode>,
+    ) -> Result<Response> {
+        if background.is_file() {
+            // Rejected right away, rather than leaving the output unchanged when drawing
+            image::image_dimensions(&background).map_err(|source| Error::Image {
+                path: background.clone(),
+                source,
+            })?;
+        } else if !slideshow::is_collection(&background) {
+            return Err(Error::Request(format!(
+                "{} is not a file or directory",
+                background.display()
+            )));
         }
-        Ok(())
+
+        let prefs = self.override_preferences(output_name)?;
+        prefs.background = Some(background);
+        if let Some(mode) = mode {
+            prefs.mode = mode;
+        }
+
+        Ok(Response::Ok)
+    }
+
+    fn set_mode(&mut self, output_name: &str, mode: config::Mode) -> Result<Response> {
+        self.override_preferences(output_name)?.mode = mode;
+
+        Ok(Response::Ok)
+    }
+
+    fn query(&self, output_name: Option<&str>) -> Result<Response> {
+        let outputs = match output_name {
+            Some(name) => vec![self.output_status(self.output(name)?)],
+            None => self
+                .outputs
+                .iter()
+                .map(|output| self.output_status(output))
+                .collect(),
+        };
+
+        Ok(Response::Status(DaemonStatus {
+            config_path: self.config.config_path.clone(),
+            config_error: self.config.error.clone(),
+            outputs,
+        }))
     }
 }
 
-fn write_image(
-    image: DynamicImage,
-    mode: &config::Mode,
-    width: u32,
-    height: u32,
-    buf: &mut std::io::BufWriter<&File>,
-) -> std::io::Result<()> {
-    buf.write_all(
-        apply_image_mode(image, mode, width, height)
-            .to_rgb8()
-            .as_raw(),
-    )?;
-    Ok(())
+/// Creates the file backing the shm pool.
+///
+/// A sealed memfd promises the compositor that the file never shrinks under its mapping.
+/// Systems without memfd get a temporary file instead.
+fn create_buffer_file() -> std::io::Result<File> {
+    match create_memfd() {
+        Ok(file) => {
+            info!("Using memfd for buffers");
+            Ok(file)
+        }
+        Err(e) => {
+            warn!("Error creating memfd, falling back to a tempfile: {}", e);
+            tempfile::tempfile()
+        }
+    }
 }
 
-fn write_default_color(
-    output: &Output,
-    buf: &mut std::io::BufWriter<&File>,
-) -> std::io::Result<()> {
-    for _ in 0..output.pixel_count {
-        buf.write(&[0, 0, 0])?;
+fn create_memfd() -> std::io::Result<File> {
+    let name = b"waypaper\0";
+    // SAFETY: `name` is nul-terminated
+    let fd = unsafe {
+        libc::memfd_create(
+            name.as_ptr().cast(),
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
     }
-    Ok(())
+    // SAFETY: The descriptor was just created and nothing else owns it
+    let file = unsafe { File::from_raw_fd(fd) };
+
+    // Growing the file stays possible, which is all the pool ever needs
+    // SAFETY: F_ADD_SEALS takes an int argument
+    let sealed = unsafe {
+        libc::fcntl(
+            file.as_raw_fd(),
+            libc::F_ADD_SEALS,
+            libc::F_SEAL_SHRINK | libc::F_SEAL_SEAL,
+        )
+    };
+    if sealed < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(file)
 }
 
-fn apply_image_mode(
-    image: DynamicImage,
-    mode: &config::Mode,
-    target_width: u32,
-    target_height: u32,
-) -> DynamicImage {
-    info!("Applying mode: {}", mode);
-    match mode {
-        config::Mode::Fill => image.resize_to_fill(
-            target_width,
-            target_height,
-            image::imageops::FilterType::Lanczos3,
-        ),
-        config::Mode::Center => todo!(),
-        config::Mode::Fit => {
-            let resized_image = image.resize(
-                target_width,
-                target_height,
-                image::imageops::FilterType::Lanczos3,
-            );
-            let mut result_image = DynamicImage::new_rgba8(target_width, target_height);
-            result_image
-                .copy_from(
-                    &resized_image,
-                    (target_width - resized_image.width()) / 2,
-                    (target_height - resized_image.height()) / 2,
-                )
-                .unwrap();
-            result_image
+fn into_response(result: Result<Response>) -> Response {
+    result.unwrap_or_else(|e| Response::Error(e.to_string()))
+}
+
+/// Work left after handling an event, done once the state lock is released.
+struct Followup {
+    redraw: bool,
+    /// Sent after redrawing, so that drawing errors reach the client.
+    reply: Option<(Sender<Response>, Response)>,
+}
+
+impl Followup {
+    fn redraw() -> Followup {
+        Followup {
+            redraw: true,
+            reply: None,
+        }
+    }
+
+    fn reply(reply: Sender<Response>, response: Response) -> Followup {
+        Followup {
+            redraw: false,
+            reply: Some((reply, response)),
+        }
+    }
+
+    /// Failed requests are answered right away and don't cause a redraw.
+    fn reply_after_redraw(reply: Sender<Response>, result: Result<Response>) -> Followup {
+        match result {
+            Ok(response) => Followup {
+                redraw: true,
+                reply: Some((reply, response)),
+            },
+            Err(e) => Followup::reply(reply, Response::Error(e.to_string())),
+        }
+    }
+
+    fn run(self, state: &Mutex<State>) -> Result<()> {
+        let result = if self.redraw { redraw(state) } else { Ok(()) };
+
+        match self.reply {
+            Some((reply, response)) => {
+                let response = match result {
+                    Err(e) => Response::Error(e.to_string()),
+                    Ok(()) => response,
+                };
+                reply.send(response).ok();
+                Ok(())
+            }
+            None => result,
         }
-        config::Mode::Stretch => image.resize_exact(
-            target_width,
-            target_height,
-            image::imageops::FilterType::Lanczos3,
-        ),
+    }
+}
+
+/// Redraws outdated outputs.
+///
+/// Frames are rendered on a thread pool while the state is unlocked, so Wayland events keep
+/// being dispatched. Only copying them into the buffer file happens under the lock.
+/// Outputs whose frame fails to render keep showing the previous one.
+fn redraw(state: &Mutex<State>) -> Result<()> {
+    let jobs = state.lock().unwrap().render_jobs();
+    debug!("Rendering {} frames", jobs.len());
+
+    let rendered = jobs
+        .into_par_iter()
+        .map(|job| {
+            let frame = job
+                .render()
+                .map_err(|e| error!("{}: {}", job.output.name, e))
+                .ok();
+            (job.spec, frame)
+        })
+        .collect();
+
+    state.lock().unwrap().present(rendered)
+}
+
+/// A frame to render, with copies of everything needed so it can be done without the state.
+struct RenderJob {
+    output: Output,
+    prefs: OutputPreferences,
+    spec: FrameSpec,
+    disk_cache: Option<DiskCache>,
+}
+
+impl RenderJob {
+    fn render(&self) -> Result<Vec<u8>> {
+        match &self.spec {
+            FrameSpec::Image(key) => {
+                render_frame(key, &self.prefs, &self.output, self.disk_cache.as_ref())
+            }
+            FrameSpec::Gradient {
+                gradient, format, ..
+            } => Ok(self.output.renderer(*format).render_gradient(gradient)),
+            FrameSpec::Color { color, format, .. } => Ok(self
+                .output
+                .renderer(*format)
+                .render_color(color.unwrap_or_default())),
+        }
+    }
+}
+
+/// Loads an image frame from the disk cache, or renders it and stores it there for the next
+/// time it is shown.
+fn render_frame(
+    key: &ImageKey,
+    prefs: &OutputPreferences,
+    output: &Output,
+    disk_cache: Option<&DiskCache>,
+) -> Result<Vec<u8>> {
+    let renderer = output.renderer(key.format);
+    let disk_key = disk_cache.and_then(|disk_cache| {
+        key.disk_key(disk_cache)
+            .map_err(|e| warn!("Error hashing {}: {}", key.path.display(), e))
+            .ok()
+    });
+
+    if let (Some(disk_cache), Some(disk_key)) = (disk_cache, &disk_key) {
+        let mut frame = vec![0; renderer.frame_len()];
+        if disk_cache.read_into(disk_key, &mut frame) {
+            info!("{}: Loaded frame from disk cache", output.name);
+            return Ok(frame);
+        }
+    }
+
+    let frame = renderer.render_file(&key.path, prefs)?;
+
+    if let (Some(disk_cache), Some(disk_key)) = (disk_cache, &disk_key) {
+        if let Err(e) = disk_cache.put(disk_key, &frame) {
+            warn!("Error writing frame to disk cache: {}", e);
+        }
+    }
+    Ok(frame)
+}
+
+/// Everything that determines the pixels written for an output.
+#[derive(Debug, Clone, PartialEq)]
+enum FrameSpec {
+    Image(ImageKey),
+    Gradient {
+        gradient: config::Gradient,
+        size: (u32, u32),
+        transform: wl_output::Transform,
+        format: wl_shm::Format,
+    },
+    Color {
+        /// `None` when nothing was configured, which is drawn black.
+        color: Option<config::Color>,
+        pixel_count: usize,
+        format: wl_shm::Format,
+    },
+}
+
+/// Identifies a decoded and scaled image in the frame cache.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ImageKey {
+    path: PathBuf,
+    /// Changes when the file is edited in place.
+    modified: Option<SystemTime>,
+    size: (u32, u32),
+    transform: wl_output::Transform,
+    format: wl_shm::Format,
+    mode: config::Mode,
+    pad_color: config::Color,
+    /// Bit pattern of the scale, as floats are not `Hash`.
+    tile_scale: Option<u32>,
+    tile_offset: (i32, i32),
+}
+
+impl ImageKey {
+    /// Identifies the frame in the disk cache, hashing the source if needed.
+    fn disk_key(&self, disk_cache: &DiskCache) -> std::io::Result<String> {
+        disk_cache.frame_key(&self.path, &self.describe())
+    }
+
+    /// Like [`ImageKey::disk_key`], but never reads the source.
+    fn known_disk_key(&self, disk_cache: &DiskCache) -> Option<String> {
+        disk_cache.known_frame_key(&self.path, &self.describe())
+    }
+
+    /// Everything but the source, which the disk cache identifies by its contents.
+    fn describe(&self) -> String {
+        format!(
+            "{}x{}-{:?}-{:?}-{}-{}-{:?}-{:?}-{:?}",
+            self.size.0,
+            self.size.1,
+            self.transform,
+            self.format,
+            self.mode,
+            self.pad_color,
+            self.tile_scale,
+            self.tile_offset,
+            RESIZE_FILTER
+        )
     }
 }
 
 #[derive(Debug, Clone)]
 struct Output {
+    global_name: u32,
     name: String,
+    /// Size of the buffer in pixels, before the transform is applied. This is the current
+    /// mode unless it can't be divided by the scale, then it's the logical size.
     width: usize,
     height: usize,
+    /// Scale of the buffer, which is 1 if the mode can't be divided by the output's scale.
+    scale: i32,
+    /// Size of the current mode, as reported to clients.
+    mode_size: (usize, usize),
+    transform: wl_output::Transform,
     pixel_count: usize,
+    description: Option<String>,
+    make: Option<String>,
+    model: Option<String>,
     wl_output: Option<wl_output::WlOutput>,
 }
 
+impl Output {
+    fn identity(&self) -> OutputIdentity {
+        OutputIdentity {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            make: self.make.clone(),
+            model: self.model.clone(),
+        }
+    }
+
+    fn geometry(&self) -> Geometry {
+        Geometry {
+            width: self.width as u32,
+            height: self.height as u32,
+            transform: self.transform,
+        }
+    }
+
+    fn renderer(&self, format: wl_shm::Format) -> Renderer {
+        Renderer::new(self.geometry(), format)
+    }
+
+    /// Size of the surface in the compositor's logical coordinate space.
+    fn logical_size(&self) -> (u32, u32) {
+        let (width, height) = self.geometry().render_size();
+        let scale = self.scale.max(1) as u32;
+        (width / scale, height / scale)
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 struct OutputBuilder {
+    pub global_name: u32,
     pub name: String,
     pub width: usize,
     pub height: usize,
+    pub scale: i32,
+    pub transform: Option<wl_output::Transform>,
+    pub description: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
     pub wl_output: Option<wl_output::WlOutput>,
 }
 
 impl OutputBuilder {
     fn build(&self) -> Output {
+        // Compositors reject buffers that aren't a multiple of their scale, so those are drawn
+        // at the logical size and scaled up by the compositor
+        let scale = self.scale.max(1);
+        let (width, height, scale) = match scale as usize {
+            factor if self.width.is_multiple_of(factor) && self.height.is_multiple_of(factor) => {
+                (self.width, self.height, scale)
+            }
+            factor => (self.width / factor, self.height / factor, 1),
+        };
+
         Output {
-            name: self.name.clone(),
-            width: self.width,
-            height: self.height,
-            pixel_count: self.width * self.height,
+            global_name: self.global_name,
+            name: match self.name.is_empty() {
+                // Outputs before version 4 have no name, but several of them still need to
+                // be told apart
+                true => format!("output-{}", self.global_name),
+                false => self.name.clone(),
+            },
+            width,
+            height,
+            scale,
+            mode_size: (self.width, self.height),
+            transform: self.transform.unwrap_or(wl_output::Transform::Normal),
+            pixel_count: width * height,
+            description: self.description.clone(),
+            make: self.make.clone(),
+            model: self.model.clone(),
             wl_output: self.wl_output.clone(),
         }
     }
@@ -374,92 +1557,99 @@ impl Dispatch<wl_registry::WlRegistry, ()> for Dispatcher {
         _: &Connection,
         qh: &QueueHandle<Self>,
     ) {
-        if let wl_registry::Event::Global {
-            name,
-            interface,
-            version,
-            ..
-        } = event
-        {
-            let state = &mut dispatcher.state.lock().unwrap();
-            match &interface[..] {
+        let state = &mut dispatcher.state.lock().unwrap();
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } => match &interface[..] {
                 "wl_compositor" => {
-                    state.globals.compositor.replace(
-                        registry.bind::<wl_compositor::WlCompositor, _, _>(name, version, qh, ()),
-                    );
+                    state.globals.compositor =
+                        state
+                            .globals
+                            .bind(registry, name, version, COMPOSITOR_VERSIONS, qh);
                 }
                 "wl_shm" => {
-                    state
-                        .globals
-                        .shm
-                        .replace(registry.bind::<wl_shm::WlShm, _, _>(name, version, qh, ()));
+                    state.globals.shm =
+                        state
+                            .globals
+                            .bind(registry, name, version, SHM_VERSIONS, qh);
                 }
                 "wl_output" => {
-                    registry.bind::<wl_output::WlOutput, usize, _>(
-                        name,
-                        version,
-                        qh,
-                        state.next_output_id(),
-                    );
+                    let version = version.min(OUTPUT_VERSION);
+                    registry.bind::<wl_output::WlOutput, u32, _>(name, version, qh, name);
                 }
                 "zwlr_layer_shell_v1" => {
-                    state
-                        .globals
-                        .layer_shell
-                        .replace(registry.bind::<ZwlrLayerShellV1, _, _>(name, version, qh, ()));
+                    state.globals.layer_shell =
+                        state
+                            .globals
+                            .bind(registry, name, version, LAYER_SHELL_VERSIONS, qh);
                 }
                 _ => {}
+            },
+            wl_registry::Event::GlobalRemove { name } => {
+                state.remove_output(name);
             }
+            _ => {}
         }
     }
 }
 
-impl Dispatch<ZwlrLayerSurfaceV1, ()> for Dispatcher {
+impl Dispatch<ZwlrLayerSurfaceV1, String> for Dispatcher {
     fn event(
         dispatcher: &mut Self,
         layer_surface: &ZwlrLayerSurfaceV1,
         event: <ZwlrLayerSurfaceV1 as wayland_client::Proxy>::Event,
-        _: &(),
+        output_name: &String,
         _: &Connection,
         qh: &QueueHandle<Self>,
     ) {
+        let state = &mut dispatcher.state.lock().unwrap();
         match event {
             zwlr_layer_surface_v1::Event::Configure {
                 serial,
                 width: _,
                 height: _,
             } => {
-                let state = &mut dispatcher.state.lock().unwrap();
                 layer_surface.ack_configure(serial);
-                state.surface_configured += 1;
+                state.configured_surfaces.insert(output_name.clone());
 
-                if state.surfaces.len() == state.surface_configured as usize {
+                if state.all_surfaces_configured() {
                     state.setup_buffers(qh);
                 }
             }
+            zwlr_layer_surface_v1::Event::Closed => {
+                // The output is about to go away, the rest is cleaned up on global_remove
+                info!("{}: Layer surface closed", output_name);
+                if let Some(layer_surface) = state.layer_surfaces.remove(output_name) {
+                    layer_surface.destroy();
+                }
+            }
             _ => {}
         }
     }
 }
 
-impl Dispatch<wl_output::WlOutput, usize> for Dispatcher {
+impl Dispatch<wl_output::WlOutput, u32> for Dispatcher {
     fn event(
         dispatcher: &mut Self,
         wl_output: &wl_output::WlOutput,
         event: wl_output::Event,
-        output_id: &usize,
+        global_name: &u32,
         _: &Connection,
         qh: &QueueHandle<Self>,
     ) {
         let state = &mut dispatcher.state.lock().unwrap();
 
-        let builder = match state.output_builders.get_mut(*output_id) {
-            Some(builder) => builder,
-            None => {
-                state.output_builders.push(OutputBuilder::default());
-                state.output_builders.last_mut().unwrap()
-            }
-        };
+        let builder = state
+            .output_builders
+            .entry(*global_name)
+            .or_insert_with(|| OutputBuilder {
+                global_name: *global_name,
+                scale: 1,
+                ..Default::default()
+            });
 
         match event {
             wl_output::Event::Mode {
@@ -468,7 +1658,9 @@ impl Dispatch<wl_output::WlOutput, usize> for Dispatcher {
                 height,
                 refresh: _,
             } => {
-                if flags != WEnum::Value(wl_output::Mode::Current) {
+                // The current mode is usually also flagged as the preferred one
+                if !matches!(flags, WEnum::Value(flags) if flags.contains(wl_output::Mode::Current))
+                {
                     return;
                 }
                 // save output mode
@@ -479,43 +1671,58 @@ impl Dispatch<wl_output::WlOutput, usize> for Dispatcher {
                 // save output name
                 builder.name = name;
             }
+            wl_output::Event::Scale { factor } => {
+                builder.scale = factor;
+            }
+            wl_output::Event::Description { description } => {
+                builder.description = Some(description);
+            }
+            wl_output::Event::Geometry {
+                make,
+                model,
+                transform,
+                ..
+            } => {
+                builder.make = Some(make);
+                builder.model = Some(model);
+                if let WEnum::Value(transform) = transform {
+                    builder.transform = Some(transform);
+                }
+            }
             wl_output::Event::Done => {
                 builder.wl_output = Some(wl_output.clone());
 
-                let builder = state.output_builders.remove(*output_id);
                 let output = builder.build();
 
-                state.total_pixels += output.pixel_count;
-
-                let surface = state
-                    .globals
-                    .compositor
-                    .as_ref()
-                    .unwrap()
-                    .create_surface(qh, ());
-
-                let layer_surface = state
-                    .globals
-                    .layer_shell
-                    .as_ref()
-                    .unwrap()
-                    .get_layer_surface(
-                        &surface,
-                        output.wl_output.as_ref(),
-                        Layer::Background,
-                        String::from("waypaper"),
-                        qh,
-                        (),
-                    );
-
-                layer_surface.set_size(output.width as u32, output.height as u32);
-                layer_surface.set_exclusive_zone(-1);
-
-                surface.commit();
-                state.surfaces.insert(output.name.clone(), surface);
-                state.outputs.push(output);
-
-                state.setup_buffer_file(qh);
+                if let Some(index) = state
+                    .outputs
+                    .iter()
+                    .position(|existing| existing.global_name == output.global_name)
+                {
+                    // Later done events carry mode, scale or transform changes
+                    info!("{}: Output changed", output.name);
+                    state.outputs[index] = output.clone();
+                    // A new scale or transform only applies with the next frame
+                    state.drawn.remove(&output.name);
+
+                    if let Some(layer_surface) = state.layer_surfaces.get(&output.name) {
+                        let (width, height) = output.logical_size();
+                        layer_surface.set_size(width, height);
+                    }
+                    if let Some(surface) = state.surfaces.get(&output.name) {
+                        surface.commit();
+                    }
+
+                    if state.all_surfaces_configured() {
+                        state.setup_buffers(qh);
+                    }
+                    return;
+                }
+
+                let name = output.name.clone();
+                if let Err(e) = state.add_output(output, qh) {
+                    error!("{}: {}", name, e);
+                }
             }
             _ => {}
         };
@@ -531,10 +1738,11 @@ impl Dispatch<wl_shm::WlShm, ()> for Dispatcher {
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
-        if let wl_shm::Event::Format { format } = event {
-            if let WEnum::Value(format) = format {
-                dispatcher.state.lock().unwrap().shm_formats.push(format);
-            }
+        if let wl_shm::Event::Format {
+            format: WEnum::Value(format),
+        } = event
+        {
+            dispatcher.state.lock().unwrap().shm_formats.push(format);
         }
     }
 }
@@ -593,13 +1801,17 @@ impl Dispatch<wl_shm_pool::WlShmPool, ()> for Dispatcher {
 
 impl Dispatch<wl_buffer::WlBuffer, ()> for Dispatcher {
     fn event(
-        _: &mut Self,
-        _: &wl_buffer::WlBuffer,
+        dispatcher: &mut Self,
+        buffer: &wl_buffer::WlBuffer,
         event: wl_buffer::Event,
         _: &(),
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
-        debug!("buffer event: {:?}", event)
+        debug!("buffer event: {:?}", event);
+
+        if let wl_buffer::Event::Release = event {
+            dispatcher.state.lock().unwrap().release_buffer(buffer);
+        }
     }
 }
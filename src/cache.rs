@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, info, warn};
+
+const CACHE_DIR_NAME: &str = "waypaper";
+
+/// Bumped whenever the layout of cached frames or their keys changes.
+const CACHE_VERSION: u32 = 2;
+
+/// Upper bound for the total size of all cached frames.
+pub const DEFAULT_MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Temporary files this old were left behind by a crash rather than being written.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Tells apart the temporary files of frames written at the same time.
+static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Pre-scaled frames on disk, so unchanged wallpapers don't have to be decoded again at startup.
+///
+/// Entries are evicted least recently used first once the cache grows past its size limit.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    /// Content hashes of source images, shared by all clones.
+    source_hashes: Arc<Mutex<HashMap<SourceVersion, u64>>>,
+}
+
+/// Identifies one version of a source image without reading it. A file replaced with one
+/// of the same size and modification time still changes its inode or change time.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SourceVersion {
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+    inode: u64,
+    changed: (i64, i64),
+}
+
+impl SourceVersion {
+    fn of(path: &Path) -> io::Result<SourceVersion> {
+        let metadata = fs::metadata(path)?;
+        Ok(SourceVersion {
+            path: path.to_path_buf(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            inode: metadata.ino(),
+            changed: (metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, max_size: u64) -> io::Result<DiskCache> {
+        fs::create_dir_all(&dir)?;
+        Ok(DiskCache {
+            dir,
+            max_size,
+            source_hashes: Arc::default(),
+        })
+    }
+
+    /// Builds the key of a frame from the contents of `source` and a `description` of
+    /// everything else that affects its pixels.
+    ///
+    /// Each version of a file is only read and hashed the first time.
+    pub fn frame_key(&self, source: &Path, description: &str) -> io::Result<String> {
+        let version = SourceVersion::of(source)?;
+        if let Some(key) = self.known_key(&version, description) {
+            return Ok(key);
+        }
+
+        let hash = hash_bytes(&fs::read(source)?);
+        let mut source_hashes = self.source_hashes.lock().unwrap();
+        // Earlier versions of the file won't be asked for again
+        source_hashes.retain(|known, _| known.path != version.path);
+        source_hashes.insert(version, hash);
+        Ok(frame_key(hash, description))
+    }
+
+    /// Like [`DiskCache::frame_key`], but `None` instead of reading `source` if that version
+    /// of it wasn't hashed yet.
+    pub fn known_frame_key(&self, source: &Path, description: &str) -> Option<String> {
+        self.known_key(&SourceVersion::of(source).ok()?, description)
+    }
+
+    fn known_key(&self, version: &SourceVersion, description: &str) -> Option<String> {
+        let hash = *self.source_hashes.lock().unwrap().get(version)?;
+        Some(frame_key(hash, description))
+    }
+
+    /// Opens the cache under `$XDG_CACHE_HOME/waypaper`.
+    pub fn open_default() -> Option<DiskCache> {
+        let dir = dirs::cache_dir()?.join(CACHE_DIR_NAME);
+        match DiskCache::new(dir, DEFAULT_MAX_SIZE) {
+            Ok(cache) => {
+                info!("Using frame cache at {}", cache.dir.display());
+                Some(cache)
+            }
+            Err(e) => {
+                warn!("Frame cache disabled: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Whether a frame of exactly `len` bytes is stored under `key`.
+    pub fn contains(&self, key: &str, len: usize) -> bool {
+        fs::metadata(self.entry_path(key)).is_ok_and(|metadata| metadata.len() == len as u64)
+    }
+
+    /// Reads the frame stored under `key` straight into `dst`, which it has to fill exactly.
+    /// Returns whether it did.
+    pub fn read_into(&self, key: &str, dst: &mut [u8]) -> bool {
+        let path = self.entry_path(key);
+        let Ok(mut file) = File::open(&path) else {
+            return false;
+        };
+
+        if !file
+            .metadata()
+            .is_ok_and(|metadata| metadata.len() == dst.len() as u64)
+        {
+            debug!("Discarding cache entry with unexpected size: {}", key);
+            fs::remove_file(&path).ok();
+            return false;
+        }
+        if let Err(e) = file.read_exact(dst) {
+            warn!("Error reading cache entry {}: {}", key, e);
+            return false;
+        }
+
+        // The modification time doubles as the last access time for eviction
+        if let Err(e) = file.set_modified(SystemTime::now()) {
+            debug!("Error touching cache entry: {}", e);
+        }
+
+        true
+    }
+
+    pub fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.entry_path(key);
+
+        // Write to a temporary file first so a crash never leaves a truncated entry behind.
+        // Other threads and daemons may be writing the same entry.
+        let temp_path = path.with_extension(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, &path)?;
+
+        self.evict()
+    }
+
+    /// Removes the least recently used entries until the cache fits its size limit.
+    ///
+    /// Runs while other threads write and evict, so entries may vanish at any point.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        let mut total_size = 0;
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            if entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                // Frames still being written are left alone
+                if metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age > STALE_TEMP_AGE)
+                {
+                    debug!("Removing stale temporary file: {}", entry.path().display());
+                    fs::remove_file(entry.path()).ok();
+                }
+                continue;
+            }
+            total_size += metadata.len();
+            entries.push((
+                metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                metadata.len(),
+                entry.path(),
+            ));
+        }
+
+        entries.sort();
+
+        for (_, size, path) in entries {
+            if total_size <= self.max_size {
+                break;
+            }
+            debug!("Evicting cache entry: {}", path.display());
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                // Evicted by another thread first
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            total_size -= size;
+        }
+
+        Ok(())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{:016x}.frame", hash_bytes(key.as_bytes())))
+    }
+}
+
+fn frame_key(source_hash: u64, description: &str) -> String {
+    format!("v{}-{:016x}-{}", CACHE_VERSION, source_hash, description)
+}
+
+/// 64-bit FNV-1a, which unlike the std hashers is stable across releases.
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
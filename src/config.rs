@@ -1,199 +1,881 @@
 use std::{
-    collections::HashMap,
-    error::Error,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
     fmt::Display,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::mpsc::{Receiver, Sender},
+    time::Duration,
 };
 
 use log::{debug, error, info, warn};
 use notify::Watcher;
+use serde::{Deserialize, Serialize};
 
-use crate::AppEvent;
+use crate::{slideshow, AppEvent, Error};
 
 const CONFIG_FILE_NAME: &str = "waypaper.ini";
 const CONFIG_DIR_NAME: &str = "waypaper";
 
+/// How long to wait for more changes before reloading the config.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Names of the section that applies to every output.
+const DEFAULT_SECTIONS: [&str; 2] = ["*", "default"];
+
+/// Factors `tile_scale` may be set to.
+pub const TILE_SCALES: RangeInclusive<f32> = 0.01..=100.0;
+
 #[derive(Debug, Default)]
 pub struct Config {
     pub config_path: Option<PathBuf>,
-    pub output_preferences: Option<HashMap<String, OutputPreferences>>,
+    /// Settings from the `[*]` or `[default]` section, which apply to every output.
+    pub default: Settings,
+    /// Output sections in the order they appear in the file.
+    pub sections: Vec<OutputSection>,
+    /// Why the config file could not be used, until it is loaded successfully.
+    pub error: Option<String>,
 }
 
 impl Config {
     pub fn search() -> Config {
         let config_file_path = Self::search_config_file();
 
-        if let Ok(path) = config_file_path {
+        if let Some(path) = config_file_path {
             info!("Config file found at: {}", path.display());
-            assert!(path.exists());
-            assert!(path.is_file());
 
-            return Config::new(path);
+            Config::new(path)
         } else {
             warn!("No config file found, using default config");
-            return Config::default();
+            Config::default()
         }
     }
 
     fn new(config_path: PathBuf) -> Config {
         info!("Loading config file");
 
-        let output_preferences = parse_config(
-            ini::Ini::load_from_file(config_path.clone()).unwrap_or_else(|e| {
-                error!("Error while loading config file: {}", e);
+        let (parsed, error) = match load(&config_path) {
+            Ok(parsed) => {
+                let error = error_summary(&parsed.diagnostics);
+                (parsed, error)
+            }
+            Err(e) => {
+                e.log();
                 warn!("Using empty config");
-                ini::Ini::new()
-            }),
-        );
+                (Parsed::default(), Some(e.to_string()))
+            }
+        };
+        for diagnostic in parsed.diagnostics.iter() {
+            diagnostic.log();
+        }
 
-        return Config {
+        // There is no earlier config to fall back to, so whatever is usable is used
+        Config {
             config_path: Some(config_path),
-            output_preferences: Some(output_preferences),
-        };
+            default: parsed.default,
+            sections: parsed.sections,
+            error,
+        }
     }
 
-    pub fn watch(
-        &self,
-    ) -> (
-        notify::RecommendedWatcher,
-        std::sync::mpsc::Receiver<AppEvent>,
-        std::sync::mpsc::Sender<AppEvent>,
-    ) {
+    /// Preferences from the section that matches `output` best, merged over the defaults.
+    ///
+    /// A section named after the connector wins over `output "..."` selectors, and selectors
+    /// without wildcards win over patterns. Ties go to the section that comes first. Returns
+    /// `None` if neither a section nor the defaults apply.
+    pub fn preferences(&self, output: &OutputIdentity) -> Option<OutputPreferences> {
+        let section = self
+            .sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| section.selector.matches(output))
+            .min_by_key(|(index, section)| (Reverse(section.selector.precedence()), *index))
+            .map(|(_, section)| &section.settings);
+
+        match section {
+            Some(settings) => Some(self.default.merge(settings).resolve()),
+            None if self.default != Settings::default() => Some(self.default.resolve()),
+            None => None,
+        }
+    }
+
+    /// Sends [`AppEvent::ConfigChanged`] whenever a config file is written, replaced or
+    /// created in one of the search locations. Returns the event channel.
+    pub fn watch(&self) -> (Receiver<AppEvent>, Sender<AppEvent>) {
         let (tx, rx) = std::sync::mpsc::channel();
+        let (changes_tx, changes_rx) = std::sync::mpsc::channel();
 
-        let sender = tx.clone();
-        let mut watcher = notify::recommended_watcher(
+        let watcher = notify::recommended_watcher(
             move |res: Result<notify::Event, notify::Error>| match res {
-                Ok(event) => {
-                    if event.kind
-                        == notify::EventKind::Access(notify::event::AccessKind::Close(
-                            notify::event::AccessMode::Write,
-                        ))
-                    {
-                        debug!("Config file changed");
-                        sender.send(AppEvent::ConfigChanged).unwrap();
-                    }
+                Ok(event) if is_config_change(&event) => {
+                    changes_tx.send(()).ok();
                 }
+                Ok(_) => {}
                 Err(e) => {
                     error!("Notify error: {}", e);
                 }
             },
-        )
-        .unwrap();
-
-        info!("Watching config file for changes");
+        );
 
-        watcher
-            .watch(
-                self.config_path.as_ref().unwrap(),
-                notify::RecursiveMode::NonRecursive,
-            )
-            .unwrap();
+        match watcher {
+            Ok(watcher) => {
+                let sender = tx.clone();
+                std::thread::spawn(move || watch_config(watcher, changes_rx, sender));
+            }
+            Err(e) => error!("Config changes won't be noticed: {}", e),
+        }
 
-        (watcher, rx, tx)
+        (rx, tx)
     }
 
-    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Replaces the settings with the current contents of the config file.
+    ///
+    /// Nothing changes if the file has any errors, so a typo doesn't take away the
+    /// wallpapers. The error is kept in [`Config::error`] until the next successful reload.
+    pub fn reload(&mut self) -> Result<(), Error> {
         info!("Reloading config file");
 
-        let config = if let Some(config_path) = &self.config_path {
-            ini::Ini::load_from_file(config_path.clone())?
-        } else {
-            return Err("Config file not found".into());
+        let result = self.try_reload();
+        if let Err(e) = &result {
+            error!("Keeping the previous config: {}", e);
+        }
+        self.error = result.as_ref().err().map(ToString::to_string);
+        result
+    }
+
+    fn try_reload(&mut self) -> Result<(), Error> {
+        // The file might have been created or moved to another search location
+        if let Some(path) = Self::search_config_file() {
+            if self.config_path.as_ref() != Some(&path) {
+                info!("Config file found at: {}", path.display());
+                self.config_path = Some(path);
+            }
+        }
+
+        let Some(config_path) = &self.config_path else {
+            return Err(Error::Config("Config file not found".to_string()));
         };
 
-        let output_preferences = parse_config(config);
+        let parsed = load(config_path).map_err(|e| Error::Config(e.to_string()))?;
+        for diagnostic in parsed.diagnostics.iter() {
+            diagnostic.log();
+        }
+        if let Some(error) = error_summary(&parsed.diagnostics) {
+            return Err(Error::Config(error));
+        }
 
-        self.output_preferences.replace(output_preferences);
+        self.default = parsed.default;
+        self.sections = parsed.sections;
 
         Ok(())
     }
 
-    fn search_config_file() -> Result<PathBuf, ()> {
-        // Try to find the config file in the following locations:
-        // 1. The current working directory
-        // 2. The user's .config directory
-        // 3. The global /etc/ directory
+    /// Returns the first config file in the search locations.
+    pub fn search_config_file() -> Option<PathBuf> {
+        info!("Searching for config file");
 
-        // If the config file is found in either of these locations, return the path to the file
-        // If the config file is not found in either of these locations, return an error
+        search_dirs()
+            .into_iter()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| {
+                debug!("Trying {}", path.display());
+                path.is_file()
+            })
+    }
+}
 
-        info!("Searching for config file");
+/// Directories that may hold the config file, in the order they are searched: the current
+/// working directory, the user's config directory and `/etc`.
+fn search_dirs() -> Vec<PathBuf> {
+    [
+        std::env::current_dir().ok(),
+        dirs::config_dir().map(|dir| dir.join(CONFIG_DIR_NAME)),
+        Some(Path::new("/etc").join(CONFIG_DIR_NAME)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Whether `event` could have changed which config file exists or what it contains.
+///
+/// Editors that save by renaming a new file over the old one produce create and rename
+/// events rather than writes, so all of them count.
+fn is_config_change(event: &notify::Event) -> bool {
+    use notify::event::{AccessKind, AccessMode, EventKind};
+
+    let relevant_kind = matches!(
+        event.kind,
+        EventKind::Create(_)
+            | EventKind::Modify(_)
+            | EventKind::Remove(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+    );
+
+    relevant_kind
+        && event.paths.iter().any(|path| {
+            path.file_name()
+                .is_some_and(|name| name == CONFIG_FILE_NAME || name == CONFIG_DIR_NAME)
+        })
+}
+
+/// Turns bursts of file changes into a single config event.
+fn watch_config(
+    mut watcher: notify::RecommendedWatcher,
+    changes: Receiver<()>,
+    sender: Sender<AppEvent>,
+) {
+    let mut watched = HashSet::new();
+    watch_search_dirs(&mut watcher, &mut watched);
+
+    while changes.recv().is_ok() {
+        // Wait for the editor to finish saving
+        while changes.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+
+        // The change might have been a new config directory
+        watch_search_dirs(&mut watcher, &mut watched);
+
+        debug!("Config file changed");
+        if sender.send(AppEvent::ConfigChanged).is_err() {
+            break;
+        }
+    }
+}
+
+/// Watches every search directory, or its parent while the directory doesn't exist yet.
+///
+/// Directories are watched rather than the file itself, since the file might not exist yet
+/// and editors often replace it with a new one.
+fn watch_search_dirs(watcher: &mut notify::RecommendedWatcher, watched: &mut HashSet<PathBuf>) {
+    for dir in search_dirs() {
+        let target = if dir.is_dir() {
+            dir
+        } else {
+            match dir.parent() {
+                Some(parent) if parent.is_dir() => parent.to_path_buf(),
+                _ => continue,
+            }
+        };
+
+        if watched.contains(&target) {
+            continue;
+        }
+
+        match watcher.watch(&target, notify::RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                info!("Watching {} for config changes", target.display());
+                watched.insert(target);
+            }
+            Err(e) => warn!("Error watching {}: {}", target.display(), e),
+        }
+    }
+}
+
+/// Keys that may appear in a section.
+const KEYS: [&str; 9] = [
+    "background",
+    "mode",
+    "color",
+    "gradient",
+    "pad_color",
+    "tile_scale",
+    "tile_offset",
+    "interval",
+    "order",
+];
+
+/// Settings read from a config file, along with the problems found on the way.
+#[derive(Debug, Default)]
+struct Parsed {
+    default: Settings,
+    sections: Vec<OutputSection>,
+    diagnostics: Vec<Diagnostic>,
+}
 
-        info!("Searching in current working directory");
+/// Checks a config file without applying it.
+pub fn check(path: &Path) -> Vec<Diagnostic> {
+    let mut diagnostics = match load(path) {
+        Ok(parsed) => parsed.diagnostics,
+        Err(diagnostic) => vec![diagnostic],
+    };
+    diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+    diagnostics
+}
+
+/// Describes the first error among `diagnostics`, if there is one.
+fn error_summary(diagnostics: &[Diagnostic]) -> Option<String> {
+    let mut errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error);
+    let first = errors.next()?;
+
+    match errors.count() {
+        0 => Some(first.to_string()),
+        more => Some(format!("{} (and {} more errors)", first, more)),
+    }
+}
+
+/// Reads a config file.
+///
+/// Only fails if the file can't be read or isn't valid INI. Values with problems are left
+/// out and reported in the diagnostics.
+fn load(path: &Path) -> Result<Parsed, Diagnostic> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        Diagnostic::new(
+            Severity::Error,
+            path,
+            None,
+            format!("Cannot read config file: {}", e),
+        )
+    })?;
+    let config = ini::Ini::load_from_str(&text)
+        .map_err(|e| Diagnostic::new(Severity::Error, path, Some(e.line), e.msg))?;
 
-        if let Ok(cwd) = std::env::current_dir() {
-            let config_file_path = cwd.join(CONFIG_FILE_NAME);
-            if config_file_path.exists() {
-                return Ok(config_file_path);
+    Ok(parse_config(&config, &LineIndex::new(&text), path))
+}
+
+fn parse_config(config: &ini::Ini, lines: &LineIndex, path: &Path) -> Parsed {
+    let mut parsed = Parsed::default();
+
+    for (section_name, properties) in config.iter() {
+        let Some(section_name) = section_name else {
+            for (key, _) in properties.iter() {
+                parsed.diagnostics.push(Diagnostic::new(
+                    Severity::Error,
+                    path,
+                    lines.key(None, key),
+                    format!("Key '{}' is not in a section", key),
+                ));
+            }
+            continue;
+        };
+
+        let selector = if DEFAULT_SECTIONS.contains(&section_name) {
+            None
+        } else {
+            match OutputSelector::from_str(section_name) {
+                Ok(selector) => Some(selector),
+                Err(_) => {
+                    parsed.diagnostics.push(Diagnostic::new(
+                        Severity::Error,
+                        path,
+                        lines.section(section_name),
+                        format!("Invalid section name '{}'", section_name),
+                    ));
+                    continue;
+                }
             }
+        };
+
+        let mut section = SectionParser {
+            name: section_name,
+            properties,
+            lines,
+            path,
+            diagnostics: &mut parsed.diagnostics,
+        };
+        section.check_keys();
+
+        let settings = Settings {
+            background: section.background(),
+            mode: section.value("mode", Mode::from_str, "center, fill, fit, stretch or tile"),
+            color: section.value("color", Color::from_str, "#rrggbb"),
+            gradient: section.value("gradient", Gradient::from_str, "<from>,<to>[,<angle>]"),
+            pad_color: section.value("pad_color", Color::from_str, "#rrggbb"),
+            tile_scale: section.value("tile_scale", parse_scale, "a number from 0.01 to 100"),
+            tile_offset: section.value("tile_offset", parse_offset, "x,y"),
+            interval: section.value("interval", parse_duration, "a duration like 30s or 15m"),
+            order: section.value("order", Order::from_str, "sequential, random or shuffle"),
+        };
+
+        match selector {
+            Some(selector) => parsed.sections.push(OutputSection { selector, settings }),
+            // Later default sections override earlier ones key by key
+            None => parsed.default = parsed.default.merge(&settings),
         }
+    }
 
-        info!("Searching in user's config directory");
+    parsed
+}
 
-        let home_config_path = dirs::config_dir()
-            .unwrap()
-            .join(CONFIG_DIR_NAME)
-            .join(CONFIG_FILE_NAME);
+/// Reads the values of one section, reporting the ones that can't be used.
+struct SectionParser<'a> {
+    name: &'a str,
+    properties: &'a ini::Properties,
+    lines: &'a LineIndex,
+    path: &'a Path,
+    diagnostics: &'a mut Vec<Diagnostic>,
+}
+
+impl SectionParser<'_> {
+    fn report(&mut self, severity: Severity, key: &str, message: String) {
+        let line = self
+            .lines
+            .key(Some(self.name), key)
+            .or_else(|| self.lines.section(self.name));
+        self.diagnostics.push(Diagnostic::new(
+            severity,
+            self.path,
+            line,
+            format!("{}: {}", self.name, message),
+        ));
+    }
 
-        if home_config_path.exists() {
-            return Ok(home_config_path);
+    fn check_keys(&mut self) {
+        let properties = self.properties;
+        for (key, _) in properties.iter() {
+            if !KEYS.contains(&key) {
+                self.report(Severity::Error, key, format!("Unknown key '{}'", key));
+            }
         }
+    }
 
-        info!("Searching in global config directory");
+    fn value<T>(
+        &mut self,
+        key: &str,
+        parse: impl Fn(&str) -> Result<T, ()>,
+        expected: &str,
+    ) -> Option<T> {
+        let value = self.properties.get(key)?;
+        match parse(value) {
+            Ok(value) => Some(value),
+            Err(()) => {
+                let message = format!("Invalid {} '{}', expected {}", key, value, expected);
+                self.report(Severity::Error, key, message);
+                None
+            }
+        }
+    }
 
-        let global_config_path = Path::new("/etc")
-            .join(CONFIG_DIR_NAME)
-            .join(CONFIG_FILE_NAME);
+    /// The background is kept even if it can't be shown yet, since it might appear later.
+    fn background(&mut self) -> Option<PathBuf> {
+        let background = PathBuf::from(self.properties.get("background")?);
 
-        if global_config_path.exists() {
-            return Ok(global_config_path);
+        if slideshow::is_collection(&background) {
+            if slideshow::list_images(&background).is_empty() {
+                let message = format!("No images found in '{}'", background.display());
+                self.report(Severity::Warning, "background", message);
+            }
+        } else if !background.exists() {
+            let message = format!("Background image '{}' not found", background.display());
+            self.report(Severity::Error, "background", message);
+        } else if let Err(e) = image::image_dimensions(&background) {
+            let message = format!("Cannot read image '{}': {}", background.display(), e);
+            self.report(Severity::Error, "background", message);
         }
 
-        Err(())
+        Some(background)
     }
 }
 
-fn parse_config(config: ini::Ini) -> HashMap<String, OutputPreferences> {
-    let mut output_preferences = HashMap::new();
+/// Where sections and keys are in the file, which rust-ini doesn't keep track of.
+#[derive(Debug, Default)]
+struct LineIndex {
+    sections: HashMap<String, usize>,
+    keys: HashMap<(Option<String>, String), usize>,
+}
+
+impl LineIndex {
+    fn new(text: &str) -> LineIndex {
+        let mut index = LineIndex::default();
+        let mut section = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with([';', '#']) {
+                continue;
+            }
+
+            if let Some((name, _)) = line.strip_prefix('[').and_then(|l| l.split_once(']')) {
+                let name = name.trim().to_string();
+                index.sections.entry(name.clone()).or_insert(number + 1);
+                section = Some(name);
+            } else if let Some((key, _)) = line.split_once(['=', ':']) {
+                index
+                    .keys
+                    .entry((section.clone(), key.trim().to_string()))
+                    .or_insert(number + 1);
+            }
+        }
+
+        index
+    }
+
+    fn section(&self, name: &str) -> Option<usize> {
+        self.sections.get(name).copied()
+    }
 
-    config
-        .sections()
-        .filter(Option::is_some)
-        .for_each(|section_name| {
-            let section = config.section(section_name);
+    fn key(&self, section: Option<&str>, key: &str) -> Option<usize> {
+        self.keys
+            .get(&(section.map(str::to_string), key.to_string()))
+            .copied()
+    }
+}
 
-            let output_name = section_name.unwrap().to_string();
+/// A problem found in a config file.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: PathBuf,
+    /// Line the problem was found on, starting at 1.
+    pub line: Option<usize>,
+    pub message: String,
+}
 
-            let background = section.and_then(|section| {
-                section
-                    .get("background")
-                    .map(|background| Path::new(background).to_path_buf())
-            });
+impl Diagnostic {
+    fn new(severity: Severity, path: &Path, line: Option<usize>, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            path: path.to_path_buf(),
+            line,
+            message,
+        }
+    }
 
-            let mode = section
-                .and_then(|section| section.get("mode").map(|mode| Mode::from_str(mode).ok()))
-                .flatten()
-                .unwrap_or_default();
+    pub fn log(&self) {
+        match self.severity {
+            Severity::Warning => warn!("{}", self),
+            Severity::Error => error!("{}", self),
+        }
+    }
+}
 
-            output_preferences.insert(output_name, OutputPreferences { background, mode });
-        });
-    output_preferences
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: ", self.path.display(), line)?,
+            None => write!(f, "{}: ", self.path.display())?,
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
 }
 
-#[derive(Debug)]
+impl std::error::Error for Diagnostic {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The config works, but probably not as intended.
+    Warning,
+    /// Part of the config is ignored.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            }
+        )
+    }
+}
+
+/// Parses the factor of `tile_scale`, which has to be in [`TILE_SCALES`].
+fn parse_scale(s: &str) -> Result<f32, ()> {
+    f32::from_str(s.trim())
+        .ok()
+        .filter(|scale| TILE_SCALES.contains(scale))
+        .ok_or(())
+}
+
+/// Parses durations like `90`, `30s`, `15m`, `2h` or `1d`, without a unit seconds are assumed.
+fn parse_duration(s: &str) -> Result<Duration, ()> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number: u64 = number.parse().map_err(|_| ())?;
+
+    let seconds = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(()),
+    };
+
+    match number.checked_mul(seconds) {
+        Some(0) | None => Err(()),
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+    }
+}
+
+/// Parses an `x,y` pixel offset.
+fn parse_offset(s: &str) -> Result<(i32, i32), ()> {
+    let (x, y) = s.split_once(',').ok_or(())?;
+    let x = x.trim().parse().map_err(|_| ())?;
+    let y = y.trim().parse().map_err(|_| ())?;
+    Ok((x, y))
+}
+
+/// What an output announced about itself, used to find its config section.
+#[derive(Debug, Clone, Default)]
+pub struct OutputIdentity {
+    /// Connector name, e.g. `DP-1`.
+    pub name: String,
+    /// Human readable description, usually make, model, serial and connector.
+    pub description: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+}
+
+/// A section of the config file along with the outputs it applies to.
+#[derive(Debug, Clone)]
+pub struct OutputSection {
+    pub selector: OutputSelector,
+    pub settings: Settings,
+}
+
+/// The keys set in a section, before defaults are filled in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub background: Option<PathBuf>,
+    pub mode: Option<Mode>,
+    pub color: Option<Color>,
+    pub gradient: Option<Gradient>,
+    pub pad_color: Option<Color>,
+    pub tile_scale: Option<f32>,
+    pub tile_offset: Option<(i32, i32)>,
+    pub interval: Option<Duration>,
+    pub order: Option<Order>,
+}
+
+impl Settings {
+    /// Keys set in `other` replace the ones in `self`.
+    pub fn merge(&self, other: &Settings) -> Settings {
+        Settings {
+            background: other.background.clone().or_else(|| self.background.clone()),
+            mode: other.mode.or(self.mode),
+            color: other.color.or(self.color),
+            gradient: other.gradient.or(self.gradient),
+            pad_color: other.pad_color.or(self.pad_color),
+            tile_scale: other.tile_scale.or(self.tile_scale),
+            tile_offset: other.tile_offset.or(self.tile_offset),
+            interval: other.interval.or(self.interval),
+            order: other.order.or(self.order),
+        }
+    }
+
+    pub fn resolve(&self) -> OutputPreferences {
+        OutputPreferences {
+            background: self.background.clone(),
+            mode: self.mode.unwrap_or_default(),
+            color: self.color,
+            gradient: self.gradient,
+            // Letterboxing blends in with the solid color unless told otherwise
+            pad_color: self.pad_color.or(self.color).unwrap_or_default(),
+            tile_scale: self.tile_scale,
+            tile_offset: self.tile_offset.unwrap_or_default(),
+            interval: self.interval,
+            order: self.order.unwrap_or_default(),
+        }
+    }
+}
+
+/// Decides which outputs a section applies to.
+#[derive(Debug, Clone)]
+pub enum OutputSelector {
+    /// `[DP-1]` matches the output with that connector name.
+    Connector(String),
+    /// `[output "Dell U2720Q*"]` matches the connector name, description, model or
+    /// make and model, ignoring case.
+    Pattern {
+        pattern: glob::Pattern,
+        /// Whether the pattern has no wildcards and can only match one exact string.
+        literal: bool,
+    },
+}
+
+impl OutputSelector {
+    pub fn matches(&self, output: &OutputIdentity) -> bool {
+        match self {
+            OutputSelector::Connector(name) => &output.name == name,
+            OutputSelector::Pattern { pattern, .. } => {
+                let options = glob::MatchOptions {
+                    case_sensitive: false,
+                    ..Default::default()
+                };
+                let matches = |candidate: &str| pattern.matches_with(candidate, options);
+
+                matches(&output.name)
+                    || output.description.as_deref().is_some_and(matches)
+                    || output.model.as_deref().is_some_and(matches)
+                    || match (&output.make, &output.model) {
+                        (Some(make), Some(model)) => matches(&format!("{} {}", make, model)),
+                        _ => false,
+                    }
+            }
+        }
+    }
+
+    /// Higher values win when several sections match the same output.
+    fn precedence(&self) -> u8 {
+        match self {
+            OutputSelector::Connector(_) => 2,
+            OutputSelector::Pattern { literal: true, .. } => 1,
+            OutputSelector::Pattern { literal: false, .. } => 0,
+        }
+    }
+}
+
+impl FromStr for OutputSelector {
+    type Err = ();
+
+    /// Parses section names, either a connector name or `output "<pattern>"`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(pattern) = s.strip_prefix("output ") else {
+            return Ok(OutputSelector::Connector(s.trim().to_string()));
+        };
+
+        let pattern = pattern.trim();
+        let pattern = pattern
+            .strip_prefix('"')
+            .and_then(|pattern| pattern.strip_suffix('"'))
+            .unwrap_or(pattern);
+
+        Ok(OutputSelector::Pattern {
+            pattern: glob::Pattern::new(pattern).map_err(|_| ())?,
+            literal: glob::Pattern::escape(pattern) == pattern,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct OutputPreferences {
+    /// Image file, or a directory or glob pattern to cycle through.
     pub background: Option<PathBuf>,
     pub mode: Mode,
+    /// Solid background used when there is neither an image nor a gradient.
+    pub color: Option<Color>,
+    /// Background used when there is no image.
+    pub gradient: Option<Gradient>,
+    /// Shown around images that do not cover the whole output.
+    pub pad_color: Color,
+    /// Factor applied to the image before it is repeated in `tile` mode.
+    pub tile_scale: Option<f32>,
+    /// Position of the first tile's top left corner relative to the output.
+    pub tile_offset: (i32, i32),
+    /// How long each image of a slideshow is shown.
+    pub interval: Option<Duration>,
+    pub order: Order,
+}
+
+/// Order in which the images of a slideshow are shown.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// Sorted by file name.
+    #[default]
+    Sequential,
+    /// Any image may come next, including ones that were shown recently.
+    Random,
+    /// Every image is shown once in random order before the cycle starts over.
+    Shuffle,
+}
+
+impl Display for Order {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Order::Sequential => "sequential",
+                Order::Random => "random",
+                Order::Shuffle => "shuffle",
+            }
+        )
+    }
+}
+
+impl FromStr for Order {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(Order::Sequential),
+            "random" => Ok(Order::Random),
+            "shuffle" => Ok(Order::Shuffle),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Linear gradient between two colors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gradient {
+    pub from: Color,
+    pub to: Color,
+    /// Direction in degrees, clockwise with 0 pointing from left to right.
+    pub angle: f32,
+}
+
+impl FromStr for Gradient {
+    type Err = ();
+
+    /// Parses gradients in the `<from>,<to>,<angle>` notation, e.g. `#000000,#336699,90`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',').map(str::trim);
+        let from = Color::from_str(parts.next().ok_or(())?)?;
+        let to = Color::from_str(parts.next().ok_or(())?)?;
+        let angle = match parts.next() {
+            Some(angle) => f32::from_str(angle)
+                .ok()
+                .filter(|angle| angle.is_finite())
+                .ok_or(())?,
+            None => 0.0,
+        };
+
+        if parts.next().is_some() {
+            return Err(());
+        }
+
+        Ok(Gradient { from, to, angle })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
 }
 
-#[derive(Debug)]
+impl FromStr for Color {
+    type Err = ();
+
+    /// Parses colors in the `#rrggbb` notation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').ok_or(())?;
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(());
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| ());
+
+        Ok(Color {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Mode {
     Center,
+    #[default]
     Fill,
     Fit,
     Stretch,
+    Tile,
 }
 
 impl Display for Mode {
@@ -206,17 +888,12 @@ impl Display for Mode {
                 Mode::Fill => "fill",
                 Mode::Fit => "fit",
                 Mode::Stretch => "stretch",
+                Mode::Tile => "tile",
             }
         )
     }
 }
 
-impl Default for Mode {
-    fn default() -> Self {
-        Mode::Fill
-    }
-}
-
 impl FromStr for Mode {
     type Err = ();
 
@@ -226,6 +903,7 @@ impl FromStr for Mode {
             "fill" => Ok(Mode::Fill),
             "fit" => Ok(Mode::Fit),
             "stretch" => Ok(Mode::Stretch),
+            "tile" => Ok(Mode::Tile),
             _ => Err(()),
         }
     }
@@ -0,0 +1,91 @@
+use std::{fmt::Display, io, path::PathBuf};
+
+use wayland_client::{backend::WaylandError, ConnectError, DispatchError};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file is missing or was rejected.
+    Config(String),
+    /// An image could not be read or decoded.
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    /// No compositor to connect to.
+    Connect(ConnectError),
+    /// The connection to the compositor broke or it sent something unexpected.
+    Wayland(DispatchError),
+    /// The compositor does not offer these globals, or only in versions that are too old.
+    MissingGlobals(Vec<String>),
+    /// A request could not be delivered to the daemon or carried out.
+    Request(String),
+    /// A message on the control socket could not be encoded or decoded.
+    Ipc(serde_json::Error),
+    Io(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Config(message) => write!(f, "{}", message),
+            Error::Image { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Connect(e) => write!(f, "Could not connect to the compositor: {}", e),
+            Error::Wayland(e) => write!(f, "Wayland error: {}", e),
+            Error::MissingGlobals(interfaces) => {
+                write!(
+                    f,
+                    "The compositor does not support {}",
+                    interfaces.join(", ")
+                )
+            }
+            Error::Request(message) => write!(f, "{}", message),
+            Error::Ipc(e) => write!(f, "Invalid message: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Image { source, .. } => Some(source),
+            Error::Connect(e) => Some(e),
+            Error::Wayland(e) => Some(e),
+            Error::Ipc(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Ipc(e)
+    }
+}
+
+impl From<ConnectError> for Error {
+    fn from(e: ConnectError) -> Self {
+        Error::Connect(e)
+    }
+}
+
+impl From<DispatchError> for Error {
+    fn from(e: DispatchError) -> Self {
+        Error::Wayland(e)
+    }
+}
+
+impl From<WaylandError> for Error {
+    fn from(e: WaylandError) -> Self {
+        Error::Wayland(DispatchError::Backend(e))
+    }
+}
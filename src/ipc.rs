@@ -0,0 +1,147 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Mode, Error, Result};
+
+/// Bumped whenever a request or response changes in an incompatible way.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const SOCKET_PREFIX: &str = "waypaper";
+
+/// Location of the control socket of the daemon running on the current Wayland display.
+///
+/// `None` without `XDG_RUNTIME_DIR`, since other users could connect to the socket or take
+/// its place in a shared directory.
+pub fn socket_path() -> Option<PathBuf> {
+    let runtime_dir = dirs::runtime_dir()?;
+    // The display may also be the absolute path of the Wayland socket
+    let display = std::env::var_os("WAYLAND_DISPLAY");
+    let display = display
+        .as_deref()
+        .and_then(|display| Path::new(display).file_name())
+        .map_or("wayland-0".into(), |name| name.to_string_lossy());
+
+    Some(runtime_dir.join(format!("{}-{}.sock", SOCKET_PREFIX, display)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message<T> {
+    pub version: u32,
+    pub body: T,
+}
+
+impl<T> Message<T> {
+    pub fn new(body: T) -> Message<T> {
+        Message {
+            version: PROTOCOL_VERSION,
+            body,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Show `background` on `output`, optionally switching the mode at the same time.
+    SetBackground {
+        output: String,
+        background: PathBuf,
+        mode: Option<Mode>,
+    },
+    SetMode {
+        output: String,
+        mode: Mode,
+    },
+    /// Drop runtime overrides and fall back to the config file, for one or all outputs.
+    Clear {
+        output: Option<String>,
+    },
+    Reload,
+    Redraw,
+    ListOutputs,
+    Query {
+        output: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", content = "data", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Outputs(Vec<OutputStatus>),
+    Status(DaemonStatus),
+    Error(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputStatus {
+    pub name: String,
+    /// As announced by the compositor, for writing `output "..."` config sections.
+    #[serde(default)]
+    pub description: Option<String>,
+    pub width: usize,
+    pub height: usize,
+    pub background: Option<PathBuf>,
+    pub mode: Mode,
+    /// Whether the preferences were set over IPC rather than read from the config file.
+    pub overridden: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub config_path: Option<PathBuf>,
+    /// Why the config file was rejected, in which case the last working one is still used.
+    #[serde(default)]
+    pub config_error: Option<String>,
+    pub outputs: Vec<OutputStatus>,
+}
+
+/// Send a single request to the daemon and wait for its response.
+pub fn send(request: Request) -> Result<Response> {
+    let path = socket_path().ok_or_else(|| {
+        Error::Request("XDG_RUNTIME_DIR is not set, can't find the daemon".to_string())
+    })?;
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| Error::Request(format!("Could not connect to {}: {}", path.display(), e)))?;
+
+    write_message(&mut stream, &Message::new(request))?;
+
+    let message: Message<Response> =
+        read_message(&mut BufReader::new(stream))?.ok_or_else(|| {
+            Error::Request("Daemon closed the connection without responding".to_string())
+        })?;
+
+    if message.version != PROTOCOL_VERSION {
+        return Err(Error::Request(format!(
+            "Protocol version mismatch (daemon: {}, client: {})",
+            message.version, PROTOCOL_VERSION
+        )));
+    }
+
+    Ok(message.body)
+}
+
+/// Messages are framed as one JSON document per line.
+pub fn write_message<T: Serialize>(writer: &mut impl Write, message: &Message<T>) -> Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns `None` once the other side has closed the connection.
+pub fn read_message<T: for<'de> Deserialize<'de>>(
+    reader: &mut impl BufRead,
+) -> Result<Option<Message<T>>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
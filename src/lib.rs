@@ -1,7 +1,49 @@
+use std::{path::PathBuf, sync::mpsc::Sender};
+
+use config::Mode;
+use ipc::Response;
+
+pub use error::{Error, Result};
+
+pub mod cache;
 pub mod config;
+pub mod error;
+pub mod ipc;
+pub mod render;
+pub mod slideshow;
 
 #[derive(Debug)]
 pub enum AppEvent {
     ConfigChanged,
     OutputChanged,
+    /// Sent periodically so slideshows can move on to their next image.
+    SlideshowTick,
+    SetBackground {
+        output: String,
+        background: PathBuf,
+        mode: Option<Mode>,
+        reply: Sender<Response>,
+    },
+    SetMode {
+        output: String,
+        mode: Mode,
+        reply: Sender<Response>,
+    },
+    ClearOverrides {
+        output: Option<String>,
+        reply: Sender<Response>,
+    },
+    Reload {
+        reply: Sender<Response>,
+    },
+    Redraw {
+        reply: Sender<Response>,
+    },
+    ListOutputs {
+        reply: Sender<Response>,
+    },
+    Query {
+        output: Option<String>,
+        reply: Sender<Response>,
+    },
 }
@@ -0,0 +1,197 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+};
+
+use waypaper::{
+    config::{self, Config, Mode, Severity},
+    ipc::{self, OutputStatus, Request, Response},
+    slideshow,
+};
+
+enum Command {
+    /// Sent to the daemon.
+    Request(Request),
+    /// Handled locally, the daemon doesn't have to be running.
+    Check(Option<PathBuf>),
+}
+
+const USAGE: &str = "\
+Usage: waypaper <command> [arguments]
+
+Commands:
+    set <output> <image> [--mode <mode>]   Show an image on an output
+    mode <output> <mode>                   Change the mode of an output
+    clear [output]                         Drop runtime changes and use the config file again
+    reload                                 Reload the config file
+    redraw                                 Repaint all outputs
+    list-outputs                           List the outputs known to the daemon
+    query [output]                         Show what is displayed on each output
+    check [path]                           Check a config file without loading it
+
+Modes: center, fill, fit, stretch, tile";
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let request = match parse_args(&args) {
+        Ok(Command::Request(request)) => request,
+        Ok(Command::Check(path)) => return check(path),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match ipc::send(request) {
+        Ok(Response::Error(e)) => {
+            eprintln!("Daemon error: {}", e);
+            ExitCode::FAILURE
+        }
+        Ok(response) => {
+            print_response(response);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("Is waypaper-daemon running?");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Command, Box<dyn Error>> {
+    let (command, args) = args.split_first().ok_or("No command given")?;
+
+    let request = match (command.as_str(), args) {
+        ("check", []) => return Ok(Command::Check(None)),
+        ("check", [path]) => return Ok(Command::Check(Some(PathBuf::from(path)))),
+        ("set", [output, image]) => Request::SetBackground {
+            output: output.clone(),
+            background: absolute_path(image)?,
+            mode: None,
+        },
+        ("set", [output, image, flag, mode]) if flag == "--mode" => Request::SetBackground {
+            output: output.clone(),
+            background: absolute_path(image)?,
+            mode: Some(parse_mode(mode)?),
+        },
+        ("mode", [output, mode]) => Request::SetMode {
+            output: output.clone(),
+            mode: parse_mode(mode)?,
+        },
+        ("clear", []) => Request::Clear { output: None },
+        ("clear", [output]) => Request::Clear {
+            output: Some(output.clone()),
+        },
+        ("reload", []) => Request::Reload,
+        ("redraw", []) => Request::Redraw,
+        ("list-outputs", []) => Request::ListOutputs,
+        ("query", []) => Request::Query { output: None },
+        ("query", [output]) => Request::Query {
+            output: Some(output.clone()),
+        },
+        ("help" | "-h" | "--help", _) => {
+            println!("{}", USAGE);
+            std::process::exit(0);
+        }
+        (
+            "set" | "mode" | "clear" | "reload" | "redraw" | "list-outputs" | "query" | "check",
+            _,
+        ) => return Err(format!("Invalid arguments for '{}'", command).into()),
+        _ => return Err(format!("Unknown command '{}'", command).into()),
+    };
+
+    Ok(Command::Request(request))
+}
+
+/// Prints every problem in the config file, failing if any of them is an error.
+fn check(path: Option<PathBuf>) -> ExitCode {
+    let Some(path) = path.or_else(Config::search_config_file) else {
+        eprintln!("No config file found");
+        return ExitCode::FAILURE;
+    };
+
+    let diagnostics = config::check(&path);
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+
+    if diagnostics.is_empty() {
+        println!("{}: OK", path.display());
+    } else {
+        println!("{} errors, {} warnings", errors, warnings);
+    }
+
+    if errors > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<Mode, Box<dyn Error>> {
+    Mode::from_str(mode).map_err(|_| format!("Unknown mode '{}'", mode).into())
+}
+
+/// The daemon does not share our working directory, so relative paths have to be resolved here.
+fn absolute_path(path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    // Patterns don't name a file that could be canonicalized, the daemon expands them
+    let resolved = if slideshow::is_glob(Path::new(path)) {
+        std::env::current_dir().map(|dir| dir.join(path))
+    } else {
+        std::fs::canonicalize(path)
+    };
+    resolved.map_err(|e| format!("{}: {}", path, e).into())
+}
+
+fn print_response(response: Response) {
+    match response {
+        Response::Ok | Response::Error(_) => {}
+        Response::Outputs(outputs) => {
+            for output in outputs {
+                match &output.description {
+                    Some(description) => println!(
+                        "{}\t{}x{}\t{}",
+                        output.name, output.width, output.height, description
+                    ),
+                    None => println!("{}\t{}x{}", output.name, output.width, output.height),
+                }
+            }
+        }
+        Response::Status(status) => {
+            match status.config_path {
+                Some(path) => println!("Config: {}", path.display()),
+                None => println!("Config: none"),
+            }
+            if let Some(error) = status.config_error {
+                println!("Config error: {}", error);
+            }
+            for output in status.outputs {
+                print_output_status(&output);
+            }
+        }
+    }
+}
+
+fn print_output_status(output: &OutputStatus) {
+    println!("{} ({}x{})", output.name, output.width, output.height);
+    match &output.background {
+        Some(background) => println!("    background: {}", background.display()),
+        None => println!("    background: none"),
+    }
+    println!("    mode: {}", output.mode);
+    if output.overridden {
+        println!("    (set at runtime)");
+    }
+}
@@ -0,0 +1,431 @@
+use std::{ops::Range, path::Path};
+
+use image::{DynamicImage, GenericImageView};
+use log::{debug, info};
+use wayland_client::protocol::{wl_output, wl_shm};
+
+use crate::{
+    config::{self, Color, Gradient, Mode, OutputPreferences},
+    slideshow, Error, Result,
+};
+
+/// Used for all scaling, part of the disk cache key as it changes the result.
+pub const RESIZE_FILTER: image::imageops::FilterType = image::imageops::FilterType::Lanczos3;
+
+/// Formats a [`Renderer`] can write, most compact first.
+pub const PREFERRED_FORMATS: [wl_shm::Format; 4] = [
+    wl_shm::Format::Bgr888,
+    wl_shm::Format::Rgb888,
+    wl_shm::Format::Xrgb8888,
+    wl_shm::Format::Argb8888,
+];
+
+/// Size and orientation of an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// Size of the current mode in hardware pixels, before the transform is applied.
+    pub width: u32,
+    pub height: u32,
+    pub transform: wl_output::Transform,
+}
+
+impl Geometry {
+    /// Whether the output is rotated by 90 or 270 degrees.
+    pub fn is_rotated(&self) -> bool {
+        use wl_output::Transform;
+
+        matches!(
+            self.transform,
+            Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270
+        )
+    }
+
+    /// Size in pixels of the image as it appears on screen.
+    pub fn render_size(&self) -> (u32, u32) {
+        if self.is_rotated() {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+/// Turns output preferences into frames exactly like the daemon draws them.
+///
+/// Frames hold the pixels of a buffer with the output's hardware size, laid out for
+/// `wl_surface.set_buffer_transform` with the output's transform, in the given `wl_shm`
+/// format and without padding between rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Renderer {
+    geometry: Geometry,
+    format: wl_shm::Format,
+}
+
+impl Renderer {
+    pub fn new(geometry: Geometry, format: wl_shm::Format) -> Renderer {
+        Renderer { geometry, format }
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    pub fn format(&self) -> wl_shm::Format {
+        self.format
+    }
+
+    /// Length in bytes of every frame.
+    pub fn frame_len(&self) -> usize {
+        self.geometry.pixel_count() * bytes_per_pixel(self.format)
+    }
+
+    /// Renders the background `prefs` describe, using the first image of a slideshow that
+    /// can be decoded.
+    pub fn render(&self, prefs: &OutputPreferences) -> Result<Vec<u8>> {
+        match &prefs.background {
+            Some(background) if slideshow::is_collection(background) => {
+                for path in slideshow::list_images(background) {
+                    match self.render_file(&path, prefs) {
+                        Ok(frame) => return Ok(frame),
+                        Err(e) => debug!("Skipping {}", e),
+                    }
+                }
+            }
+            Some(path) => return self.render_file(path, prefs),
+            None => {}
+        }
+
+        if let Some(gradient) = &prefs.gradient {
+            Ok(self.render_gradient(gradient))
+        } else {
+            Ok(self.render_color(prefs.color.unwrap_or_default()))
+        }
+    }
+
+    /// Decodes an image file and renders it with the mode of `prefs`.
+    pub fn render_file(&self, path: &Path, prefs: &OutputPreferences) -> Result<Vec<u8>> {
+        info!("Loading image: {}", path.display());
+        let image = image::io::Reader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(image::ImageError::IoError)
+            .and_then(|reader| reader.decode())
+            .map_err(|source| Error::Image {
+                path: path.to_path_buf(),
+                source,
+            })?;
+
+        debug!("Image size: {:?}", image.dimensions());
+
+        Ok(self.render_image(image, prefs))
+    }
+
+    pub fn render_image(&self, image: DynamicImage, prefs: &OutputPreferences) -> Vec<u8> {
+        let (width, height) = self.geometry.render_size();
+        self.encode(apply_image_mode(image, prefs, width, height))
+    }
+
+    pub fn render_gradient(&self, gradient: &Gradient) -> Vec<u8> {
+        let (width, height) = self.geometry.render_size();
+        self.encode(gradient_image(gradient, width, height))
+    }
+
+    pub fn render_color(&self, color: Color) -> Vec<u8> {
+        let mut frame = vec![0; self.frame_len()];
+        fill_color(&mut frame, color, self.format);
+        frame
+    }
+
+    /// Converts an image that already has the on-screen size of the output into a frame.
+    fn encode(&self, image: DynamicImage) -> Vec<u8> {
+        encode_pixels(
+            &apply_transform(image, self.geometry.transform).to_rgba8(),
+            self.format,
+        )
+    }
+}
+
+pub fn bytes_per_pixel(format: wl_shm::Format) -> usize {
+    match format {
+        wl_shm::Format::Bgr888 | wl_shm::Format::Rgb888 => 3,
+        _ => 4,
+    }
+}
+
+/// Fills `dst` with one color, doubling the filled part with every copy.
+pub fn fill_color(dst: &mut [u8], color: Color, format: wl_shm::Format) {
+    let bytes_per_pixel = bytes_per_pixel(format);
+    if dst.len() < bytes_per_pixel {
+        return;
+    }
+
+    encode_pixel(
+        [color.r, color.g, color.b, u8::MAX],
+        format,
+        &mut dst[..bytes_per_pixel],
+    );
+
+    let mut filled = bytes_per_pixel;
+    while filled < dst.len() {
+        let len = filled.min(dst.len() - filled);
+        dst.copy_within(..len, filled);
+        filled += len;
+    }
+}
+
+/// Converts RGBA pixels into the byte layout of `format`.
+fn encode_pixels(image: &image::RgbaImage, format: wl_shm::Format) -> Vec<u8> {
+    let bytes_per_pixel = bytes_per_pixel(format);
+    let mut bytes = vec![0; image.width() as usize * image.height() as usize * bytes_per_pixel];
+    for (dst, pixel) in bytes.chunks_exact_mut(bytes_per_pixel).zip(image.pixels()) {
+        encode_pixel(pixel.0, format, dst);
+    }
+    bytes
+}
+
+/// wl_shm formats describe little-endian words, so e.g. Xrgb8888 is stored as B, G, R, X.
+fn encode_pixel([r, g, b, _]: [u8; 4], format: wl_shm::Format, dst: &mut [u8]) {
+    match format {
+        wl_shm::Format::Bgr888 => dst.copy_from_slice(&[r, g, b]),
+        wl_shm::Format::Rgb888 => dst.copy_from_slice(&[b, g, r]),
+        // The wallpaper is always opaque
+        _ => dst.copy_from_slice(&[b, g, r, u8::MAX]),
+    }
+}
+
+/// Converts an image as it should appear on screen into the layout of a buffer with the
+/// given `wl_surface.set_buffer_transform`, which the compositor then undoes.
+fn apply_transform(image: DynamicImage, transform: wl_output::Transform) -> DynamicImage {
+    use wl_output::Transform;
+
+    // Transforms rotate counter-clockwise while the image crate rotates clockwise
+    match transform {
+        Transform::Normal => image,
+        Transform::_90 => image.rotate270(),
+        Transform::_180 => image.rotate180(),
+        Transform::_270 => image.rotate90(),
+        Transform::Flipped => image.fliph(),
+        Transform::Flipped90 => image.fliph().rotate270(),
+        Transform::Flipped180 => image.fliph().rotate180(),
+        Transform::Flipped270 => image.fliph().rotate90(),
+        _ => image,
+    }
+}
+
+/// Scales, crops or repeats `image` according to `prefs.mode` so it covers a
+/// `target_width` by `target_height` canvas.
+pub fn apply_image_mode(
+    image: DynamicImage,
+    prefs: &OutputPreferences,
+    target_width: u32,
+    target_height: u32,
+) -> DynamicImage {
+    info!("Applying mode: {}", prefs.mode);
+    match prefs.mode {
+        Mode::Fill => image.resize_to_fill(target_width, target_height, RESIZE_FILTER),
+        Mode::Center => center_on_canvas(&image, prefs.pad_color, target_width, target_height),
+        Mode::Fit => {
+            let resized_image = image.resize(target_width, target_height, RESIZE_FILTER);
+            center_on_canvas(&resized_image, prefs.pad_color, target_width, target_height)
+        }
+        Mode::Stretch => image.resize_exact(target_width, target_height, RESIZE_FILTER),
+        Mode::Tile => {
+            let scale = prefs.tile_scale.map_or(1.0, |scale| {
+                scale.clamp(*config::TILE_SCALES.start(), *config::TILE_SCALES.end())
+            });
+            let tile_size = (
+                ((image.width() as f32 * scale).round() as u32).max(1),
+                ((image.height() as f32 * scale).round() as u32).max(1),
+            );
+
+            // Only the parts of a tile larger than the output that show are scaled, so
+            // large scales don't allocate the whole tile
+            let (columns, offset_x) =
+                visible_tile_ranges(tile_size.0, target_width, prefs.tile_offset.0);
+            let (rows, offset_y) =
+                visible_tile_ranges(tile_size.1, target_height, prefs.tile_offset.1);
+            let tile = scaled_tile_parts(&image, tile_size, &columns, &rows);
+
+            tile_on_canvas(
+                &tile,
+                (offset_x, offset_y),
+                prefs.pad_color,
+                target_width,
+                target_height,
+            )
+        }
+    }
+}
+
+/// Places `image` unscaled in the middle of a canvas filled with `color`.
+///
+/// Parts of the image that do not fit on the canvas are cropped.
+fn center_on_canvas(image: &DynamicImage, color: Color, width: u32, height: u32) -> DynamicImage {
+    let mut canvas = image::RgbaImage::from_pixel(
+        width,
+        height,
+        image::Rgba([color.r, color.g, color.b, u8::MAX]),
+    );
+
+    let x = (i64::from(width) - i64::from(image.width())) / 2;
+    let y = (i64::from(height) - i64::from(image.height())) / 2;
+    image::imageops::overlay(&mut canvas, &image.to_rgba8(), x, y);
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Repeats `tile` over a canvas of the given size, starting at `offset`.
+fn tile_on_canvas(
+    tile: &DynamicImage,
+    offset: (i32, i32),
+    color: Color,
+    width: u32,
+    height: u32,
+) -> DynamicImage {
+    let mut canvas = image::RgbaImage::from_pixel(
+        width,
+        height,
+        image::Rgba([color.r, color.g, color.b, u8::MAX]),
+    );
+
+    let tile = tile.to_rgba8();
+    let tile_width = i64::from(tile.width());
+    let tile_height = i64::from(tile.height());
+
+    // Begin with the tile covering the top left corner, which may start off-screen
+    let start_x = i64::from(offset.0).rem_euclid(tile_width) - tile_width;
+    let start_y = i64::from(offset.1).rem_euclid(tile_height) - tile_height;
+
+    let mut y = start_y;
+    while y < i64::from(height) {
+        let mut x = start_x;
+        while x < i64::from(width) {
+            image::imageops::overlay(&mut canvas, &tile, x, y);
+            x += tile_width;
+        }
+        y += tile_height;
+    }
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// The parts of a tile of `len` pixels that show on `target` pixels when it is repeated
+/// from `offset`, in the order they appear, and the offset that repeats them in place.
+///
+/// The second part is where the tile wraps around and is empty if it doesn't.
+fn visible_tile_ranges(len: u32, target: u32, offset: i32) -> ([Range<u32>; 2], i32) {
+    if len <= target {
+        return ([0..len, 0..0], offset);
+    }
+
+    // The tile column at the left edge, the rest of the tile follows until it wraps around
+    let start = (-i64::from(offset)).rem_euclid(i64::from(len)) as u32;
+    if start + target <= len {
+        ([start..start + target, 0..0], 0)
+    } else {
+        ([start..len, 0..start + target - len], 0)
+    }
+}
+
+/// Scales `image` to a tile of `tile_size` and puts the given columns and rows of it
+/// side by side, without scaling the rest.
+fn scaled_tile_parts(
+    image: &DynamicImage,
+    tile_size: (u32, u32),
+    columns: &[Range<u32>; 2],
+    rows: &[Range<u32>; 2],
+) -> DynamicImage {
+    if columns[1].is_empty() && rows[1].is_empty() {
+        return scaled_tile_part(image, tile_size, &columns[0], &rows[0]);
+    }
+
+    let width = columns.iter().map(ExactSizeIterator::len).sum::<usize>() as u32;
+    let height = rows.iter().map(ExactSizeIterator::len).sum::<usize>() as u32;
+    let mut parts = image::RgbaImage::new(width, height);
+
+    let mut y = 0;
+    for rows in rows.iter().filter(|rows| !rows.is_empty()) {
+        let mut x = 0;
+        for columns in columns.iter().filter(|columns| !columns.is_empty()) {
+            let part = scaled_tile_part(image, tile_size, columns, rows);
+            image::imageops::replace(&mut parts, &part.to_rgba8(), x, y);
+            x += columns.len() as i64;
+        }
+        y += rows.len() as i64;
+    }
+
+    DynamicImage::ImageRgba8(parts)
+}
+
+/// The given columns and rows of `image` scaled to `tile_size`.
+fn scaled_tile_part(
+    image: &DynamicImage,
+    tile_size: (u32, u32),
+    columns: &Range<u32>,
+    rows: &Range<u32>,
+) -> DynamicImage {
+    let (source_x, width, x) = source_window(image.width(), tile_size.0, columns);
+    let (source_y, height, y) = source_window(image.height(), tile_size.1, rows);
+
+    let part = image.crop_imm(
+        source_x.start,
+        source_y.start,
+        source_x.len() as u32,
+        source_y.len() as u32,
+    );
+    let part = if part.dimensions() == (width, height) {
+        part
+    } else {
+        part.resize_exact(width, height, RESIZE_FILTER)
+    };
+
+    if (x, y, columns.len() as u32, rows.len() as u32) == (0, 0, width, height) {
+        part
+    } else {
+        part.crop_imm(x, y, columns.len() as u32, rows.len() as u32)
+    }
+}
+
+/// The source pixels that `visible` pixels of an image of `len` scaled to `scaled_len` are
+/// sampled from, the length those are scaled to and where `visible` starts in the result.
+fn source_window(len: u32, scaled_len: u32, visible: &Range<u32>) -> (Range<u32>, u32, u32) {
+    if *visible == (0..scaled_len) {
+        return (0..len, scaled_len, 0);
+    }
+
+    // Lanczos3 reaches 3 pixels to each side, more when scaling down
+    let scale = f64::from(scaled_len) / f64::from(len);
+    let margin = (3.0 / scale.min(1.0)).ceil() + 1.0;
+    let start = (f64::from(visible.start) / scale - margin).floor().max(0.0) as u32;
+    let end = ((f64::from(visible.end) / scale + margin).ceil() as u32).min(len);
+
+    let offset = (f64::from(visible.start) - f64::from(start) * scale).round() as u32;
+    let scaled =
+        ((f64::from(end - start) * scale).round() as u32).max(offset + visible.len() as u32);
+    (start..end, scaled, offset)
+}
+
+fn gradient_image(gradient: &Gradient, width: u32, height: u32) -> DynamicImage {
+    let (sin, cos) = gradient.angle.to_radians().sin_cos();
+
+    // Distance from the center to the corners along the gradient direction
+    let half_length = ((width as f32 * cos).abs() + (height as f32 * sin).abs()) / 2.0;
+    let half_length = half_length.max(f32::EPSILON);
+
+    let from = [gradient.from.r, gradient.from.g, gradient.from.b];
+    let to = [gradient.to.r, gradient.to.g, gradient.to.b];
+
+    let image = image::RgbaImage::from_fn(width, height, |x, y| {
+        let dx = x as f32 + 0.5 - width as f32 / 2.0;
+        let dy = y as f32 + 0.5 - height as f32 / 2.0;
+        let t = ((dx * cos + dy * sin) / half_length / 2.0 + 0.5).clamp(0.0, 1.0);
+
+        let mix = |i: usize| (from[i] as f32 + (to[i] as f32 - from[i] as f32) * t).round() as u8;
+        image::Rgba([mix(0), mix(1), mix(2), u8::MAX])
+    });
+
+    DynamicImage::ImageRgba8(image)
+}
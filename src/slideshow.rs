@@ -0,0 +1,208 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::config::Order;
+
+/// Used when a slideshow has no `interval` configured.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Whether `background` names a set of images rather than a single file.
+pub fn is_collection(background: &Path) -> bool {
+    background.is_dir() || is_glob(background)
+}
+
+/// Whether `path` is a pattern like `~/pictures/*.png`.
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Lists the images in a directory or matching a glob pattern, sorted by path.
+///
+/// Only files with an extension the `image` crate knows are listed. They aren't opened, so
+/// whether they decode is only found out once they are shown.
+pub fn list_images(source: &Path) -> Vec<PathBuf> {
+    let mut images: Vec<PathBuf> = if source.is_dir() {
+        match std::fs::read_dir(source) {
+            Ok(entries) => entries.filter_map(Result::ok).map(|e| e.path()).collect(),
+            Err(e) => {
+                warn!("Error reading {}: {}", source.display(), e);
+                Vec::new()
+            }
+        }
+    } else {
+        match glob::glob(&source.to_string_lossy()) {
+            Ok(paths) => paths.filter_map(Result::ok).collect(),
+            Err(e) => {
+                warn!("Invalid pattern {}: {}", source.display(), e);
+                Vec::new()
+            }
+        }
+    };
+
+    images.retain(|path| is_image_file(path));
+    images.sort();
+    images
+}
+
+fn is_image_file(path: &Path) -> bool {
+    if image::ImageFormat::from_path(path).is_err() {
+        debug!("Skipping {}: Not an image file", path.display());
+        return false;
+    }
+    path.is_file()
+}
+
+/// Cycles through the images of a directory or glob pattern.
+#[derive(Debug)]
+pub struct Slideshow {
+    source: PathBuf,
+    interval: Duration,
+    order: Order,
+    /// Images in the order they are shown.
+    images: Vec<PathBuf>,
+    /// Images that failed to decode, left out until the slideshow is restarted.
+    broken: HashSet<PathBuf>,
+    position: usize,
+    /// When the current image was first shown.
+    shown_at: Instant,
+}
+
+impl Slideshow {
+    pub fn new(source: &Path, interval: Duration, order: Order) -> Slideshow {
+        let mut slideshow = Slideshow {
+            source: source.to_path_buf(),
+            interval,
+            order,
+            images: Vec::new(),
+            broken: HashSet::new(),
+            position: 0,
+            shown_at: Instant::now(),
+        };
+
+        slideshow.rescan();
+        if order == Order::Random && !slideshow.images.is_empty() {
+            slideshow.position = fastrand::usize(..slideshow.images.len());
+        }
+
+        info!(
+            "Starting slideshow of {} images from {}",
+            slideshow.images.len(),
+            source.display()
+        );
+
+        slideshow
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Applies a new interval and order without restarting the slideshow.
+    ///
+    /// The current image stays on screen, and the timer keeps counting from when it was
+    /// first shown.
+    pub fn set_timing(&mut self, interval: Duration, order: Order) {
+        self.interval = interval;
+        if order == self.order {
+            return;
+        }
+
+        info!("Changing slideshow order to {}", order);
+        self.order = order;
+        let current = self.current().map(Path::to_path_buf);
+        match order {
+            Order::Shuffle => fastrand::shuffle(&mut self.images),
+            Order::Sequential | Order::Random => self.images.sort(),
+        }
+        self.position = current
+            .and_then(|current| self.images.iter().position(|image| *image == current))
+            .unwrap_or(0);
+    }
+
+    pub fn current(&self) -> Option<&Path> {
+        self.images.get(self.position).map(PathBuf::as_path)
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.shown_at + self.interval
+    }
+
+    /// Leaves out `image` if it is the current one, which could not be decoded, and moves on
+    /// to the next image without restarting the timer. Returns whether anything changed.
+    pub fn skip_broken(&mut self, image: &Path) -> bool {
+        if self.current() != Some(image) {
+            return false;
+        }
+
+        warn!("Leaving {} out of the slideshow", image.display());
+        self.broken.insert(image.to_path_buf());
+        self.images.remove(self.position);
+        if self.position >= self.images.len() {
+            self.position = 0;
+        }
+        true
+    }
+
+    /// Moves on to the next image and restarts the timer.
+    pub fn advance(&mut self) {
+        self.shown_at = Instant::now();
+        let current = self.current().map(Path::to_path_buf);
+
+        match self.order {
+            Order::Sequential => {
+                // Pick up files that were added or removed since the last change
+                self.rescan();
+                self.position = match &current {
+                    Some(current) => self.images.partition_point(|image| image <= current),
+                    None => 0,
+                };
+                if self.position >= self.images.len() {
+                    self.position = 0;
+                }
+            }
+            Order::Random => {
+                self.rescan();
+                self.position = match self.images.len() {
+                    0 => 0,
+                    1 => 0,
+                    len => loop {
+                        let position = fastrand::usize(..len);
+                        if self.images.get(position) != current.as_ref() {
+                            break position;
+                        }
+                    },
+                };
+            }
+            Order::Shuffle => {
+                self.position += 1;
+                if self.position >= self.images.len() {
+                    self.rescan();
+                    self.position = 0;
+
+                    // Avoid showing the same image twice in a row across cycles
+                    if self.images.len() > 1 && self.images.first() == current.as_ref() {
+                        let last = self.images.len() - 1;
+                        self.images.swap(0, last);
+                    }
+                }
+            }
+        }
+
+        if let Some(image) = self.current() {
+            debug!("Next slideshow image: {}", image.display());
+        }
+    }
+
+    fn rescan(&mut self) {
+        self.images = list_images(&self.source);
+        self.images.retain(|image| !self.broken.contains(image));
+        if self.order == Order::Shuffle {
+            fastrand::shuffle(&mut self.images);
+        }
+    }
+}
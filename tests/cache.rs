@@ -0,0 +1,78 @@
+//! Tests for `waypaper::cache`.
+
+use waypaper::cache::DiskCache;
+
+#[test]
+fn frames_are_read_straight_into_the_buffer() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = DiskCache::new(dir.path().join("cache"), 1024).unwrap();
+    cache.put("frame", &[1, 2, 3, 4]).unwrap();
+
+    let mut buffer = [0; 4];
+    assert!(cache.contains("frame", 4));
+    assert!(cache.read_into("frame", &mut buffer));
+    assert_eq!(buffer, [1, 2, 3, 4]);
+
+    // A frame for a different output size is of no use
+    let mut buffer = [0; 3];
+    assert!(!cache.contains("frame", 3));
+    assert!(!cache.read_into("frame", &mut buffer));
+    assert_eq!(buffer, [0; 3]);
+    assert!(!cache.contains("missing", 4));
+}
+
+#[test]
+fn keys_follow_the_contents_of_the_source() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = DiskCache::new(dir.path().join("cache"), 1024).unwrap();
+    let source = dir.path().join("wall.png");
+    std::fs::write(&source, "first").unwrap();
+    let modified = std::fs::metadata(&source).unwrap().modified().unwrap();
+
+    assert_eq!(cache.known_frame_key(&source, "16x9"), None);
+    let key = cache.frame_key(&source, "16x9").unwrap();
+    assert_eq!(cache.known_frame_key(&source, "16x9"), Some(key.clone()));
+    assert_ne!(cache.frame_key(&source, "9x16").unwrap(), key);
+
+    // Replaced like `cp -p` does, with the same size and modification time
+    let replacement = dir.path().join("new.png");
+    std::fs::write(&replacement, "other").unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&replacement)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    std::fs::rename(&replacement, &source).unwrap();
+    assert_eq!(cache.known_frame_key(&source, "16x9"), None);
+    assert_ne!(cache.frame_key(&source, "16x9").unwrap(), key);
+
+    // The same contents elsewhere share their frames
+    std::fs::write(&replacement, "first").unwrap();
+    assert_eq!(cache.frame_key(&replacement, "16x9").unwrap(), key);
+}
+
+#[test]
+fn frames_can_be_stored_from_several_threads() {
+    let dir = tempfile::tempdir().unwrap();
+    // Room for a few frames, so every write evicts some
+    let cache = DiskCache::new(dir.path().join("cache"), 4 * 1024).unwrap();
+
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let cache = &cache;
+            scope.spawn(move || {
+                for frame in 0..50 {
+                    let key = format!("{}-{}", thread, frame % 5);
+                    cache.put(&key, &[thread as u8; 1024]).unwrap();
+                }
+            });
+        }
+    });
+
+    let size: u64 = std::fs::read_dir(dir.path().join("cache"))
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(size <= 4 * 1024, "{} bytes left", size);
+}
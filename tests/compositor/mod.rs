@@ -0,0 +1,848 @@
+//! A minimal in-process Wayland compositor for running `waypaper-daemon` headless.
+//!
+//! It advertises `wl_compositor`, `wl_shm`, `zwlr_layer_shell_v1` and scripted `wl_output`s,
+//! which can be plugged in, unplugged and changed while the daemon runs. It records
+//! configures, acks and the pixels of every committed buffer. The daemon's log ends up in
+//! `daemon.log` in its directory.
+
+use std::{
+    fs::File,
+    os::{fd::AsRawFd, unix::fs::FileExt, unix::net::UnixStream, unix::process::CommandExt},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use wayland_protocols_wlr::layer_shell::v1::server::{
+    zwlr_layer_shell_v1::{self, ZwlrLayerShellV1},
+    zwlr_layer_surface_v1::{self, ZwlrLayerSurfaceV1},
+};
+use wayland_server::{
+    backend::{ClientData, ClientId, DisconnectReason, GlobalId},
+    protocol::{
+        wl_buffer::{self, WlBuffer},
+        wl_compositor::{self, WlCompositor},
+        wl_output::{self, WlOutput},
+        wl_region::{self, WlRegion},
+        wl_shm::{self, WlShm},
+        wl_shm_pool::{self, WlShmPool},
+        wl_surface::{self, WlSurface},
+    },
+    Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+};
+
+/// How long to wait for the daemon before a test fails.
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// An output as announced to the daemon.
+#[derive(Debug, Clone)]
+pub struct OutputSpec {
+    pub name: String,
+    pub description: String,
+    pub make: String,
+    pub model: String,
+    pub width: i32,
+    pub height: i32,
+    pub scale: i32,
+    pub transform: wl_output::Transform,
+}
+
+impl OutputSpec {
+    pub fn new(name: &str, width: i32, height: i32) -> OutputSpec {
+        OutputSpec {
+            name: name.to_string(),
+            description: format!("Mock Display ({})", name),
+            make: "Mock".to_string(),
+            model: "Display".to_string(),
+            width,
+            height,
+            scale: 1,
+            transform: wl_output::Transform::Normal,
+        }
+    }
+}
+
+/// Versions of the globals to advertise, `None` leaves a global out.
+#[derive(Debug, Clone, Copy)]
+pub struct Globals {
+    pub compositor: Option<u32>,
+    pub shm: Option<u32>,
+    pub layer_shell: Option<u32>,
+    pub output: u32,
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        Globals {
+            compositor: Some(4),
+            shm: Some(1),
+            layer_shell: Some(4),
+            output: 4,
+        }
+    }
+}
+
+/// Something that happened on a surface, in the order the compositor saw it.
+///
+/// `Overwritten` means the pixels of a buffer changed while it was still on screen.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Configure { surface: u32, serial: u32 },
+    AckConfigure { surface: u32, serial: u32 },
+    Commit(Commit),
+    Overwritten { buffer: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Commit {
+    /// Protocol id of the `wl_surface`.
+    pub surface: u32,
+    /// Name of the output the layer surface was created for.
+    pub output: Option<String>,
+    /// Size requested with `set_size`.
+    pub requested_size: (u32, u32),
+    pub scale: i32,
+    pub transform: wl_output::Transform,
+    /// The buffer attached with this commit.
+    pub frame: Option<Frame>,
+}
+
+/// Contents of a committed `wl_buffer`.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Protocol id of the `wl_buffer`.
+    pub buffer: u32,
+    pub width: i32,
+    pub height: i32,
+    pub stride: i32,
+    pub format: wl_shm::Format,
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    /// The bytes of the pixel at `x`, `y`.
+    pub fn pixel(&self, x: i32, y: i32) -> &[u8] {
+        let bytes_per_pixel = (self.stride / self.width) as usize;
+        let start = (y * self.stride) as usize + x as usize * bytes_per_pixel;
+        &self.pixels[start..start + bytes_per_pixel]
+    }
+}
+
+pub struct State {
+    /// Every output announced so far, unplugged ones included.
+    pub outputs: Vec<OutputSpec>,
+    pub formats: Vec<wl_shm::Format>,
+    pub events: Vec<Event>,
+    next_serial: u32,
+    /// Bound `wl_output`s, with the index of their output as user data.
+    bound_outputs: Vec<WlOutput>,
+    layer_surfaces: Vec<ZwlrLayerSurfaceV1>,
+}
+
+impl State {
+    pub fn commits(&self) -> impl Iterator<Item = &Commit> {
+        self.events.iter().filter_map(|event| match event {
+            Event::Commit(commit) => Some(commit),
+            _ => None,
+        })
+    }
+
+    /// The frame currently shown on an output.
+    pub fn frame(&self, output: &str) -> Option<&Frame> {
+        self.commits()
+            .filter(|commit| commit.output.as_deref() == Some(output))
+            .filter_map(|commit| commit.frame.as_ref())
+            .last()
+    }
+
+    /// Buffers whose pixels changed while they were on screen.
+    pub fn overwritten(&self) -> Vec<u32> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Overwritten { buffer, .. } => Some(*buffer),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Number of buffers committed to an output so far.
+    pub fn frame_count(&self, output: &str) -> usize {
+        self.commits()
+            .filter(|commit| commit.output.as_deref() == Some(output) && commit.frame.is_some())
+            .count()
+    }
+}
+
+/// Runs `waypaper-daemon` against the mock compositor, in temporary runtime, cache and
+/// config directories.
+pub struct Compositor {
+    display: Display<State>,
+    pub state: State,
+    daemon: Child,
+    dir: tempfile::TempDir,
+    output_version: u32,
+    /// Globals of the outputs in `state.outputs`, `None` once unplugged.
+    output_globals: Vec<Option<GlobalId>>,
+}
+
+impl Compositor {
+    /// Starts the daemon with `config` as its `waypaper.ini`.
+    pub fn start(outputs: Vec<OutputSpec>, formats: Vec<wl_shm::Format>, config: &str) -> Self {
+        Self::start_with(Globals::default(), outputs, formats, config)
+    }
+
+    /// Like `start`, but only advertises `globals`.
+    pub fn start_with(
+        globals: Globals,
+        outputs: Vec<OutputSpec>,
+        formats: Vec<wl_shm::Format>,
+        config: &str,
+    ) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        for subdir in ["runtime", "cache", "config", "home"] {
+            std::fs::create_dir(dir.path().join(subdir)).unwrap();
+        }
+        std::fs::write(dir.path().join("waypaper.ini"), config).unwrap();
+
+        let display = Display::<State>::new().unwrap();
+        let handle = display.handle();
+        if let Some(version) = globals.compositor {
+            handle.create_global::<State, WlCompositor, _>(version, ());
+        }
+        if let Some(version) = globals.shm {
+            handle.create_global::<State, WlShm, _>(version, ());
+        }
+        if let Some(version) = globals.layer_shell {
+            handle.create_global::<State, ZwlrLayerShellV1, _>(version, ());
+        }
+        let output_globals = (0..outputs.len())
+            .map(|index| Some(handle.create_global::<State, WlOutput, _>(globals.output, index)))
+            .collect();
+
+        let (server, client) = UnixStream::pair().unwrap();
+        display
+            .handle()
+            .insert_client(server, Arc::new(NoClientData))
+            .unwrap();
+
+        let client_fd = client.as_raw_fd();
+        let mut command = Command::new(env!("CARGO_BIN_EXE_waypaper-daemon"));
+        command
+            .current_dir(dir.path())
+            .env("WAYLAND_SOCKET", client_fd.to_string())
+            .env_remove("WAYLAND_DISPLAY")
+            .env("XDG_RUNTIME_DIR", dir.path().join("runtime"))
+            .env("XDG_CACHE_HOME", dir.path().join("cache"))
+            .env("XDG_CONFIG_HOME", dir.path().join("config"))
+            .env("HOME", dir.path().join("home"))
+            .env(
+                "RUST_LOG",
+                std::env::var("RUST_LOG").unwrap_or_else(|_| "warn".to_string()),
+            )
+            .stderr(File::create(dir.path().join("daemon.log")).unwrap());
+        // SAFETY: Only calls fcntl, which is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                // The socket has to survive exec to be usable through WAYLAND_SOCKET
+                if libc::fcntl(client_fd, libc::F_SETFD, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let daemon = command.spawn().unwrap();
+        drop(client);
+
+        Compositor {
+            display,
+            state: State {
+                outputs,
+                formats,
+                events: Vec::new(),
+                next_serial: 1,
+                bound_outputs: Vec::new(),
+                layer_surfaces: Vec::new(),
+            },
+            daemon,
+            dir,
+            output_version: globals.output,
+            output_globals,
+        }
+    }
+
+    /// Announces a new output, like plugging in a monitor.
+    pub fn add_output(&mut self, spec: OutputSpec) {
+        let index = self.state.outputs.len();
+        self.state.outputs.push(spec);
+        let global = self
+            .display
+            .handle()
+            .create_global::<State, WlOutput, _>(self.output_version, index);
+        self.output_globals.push(Some(global));
+    }
+
+    /// Removes an output, like unplugging a monitor. Its layer surfaces are closed first.
+    pub fn remove_output(&mut self, name: &str) {
+        let index = self.output_index(name);
+        for layer_surface in self.state.layer_surfaces.iter() {
+            let data = layer_surface.data::<WlSurface>().unwrap();
+            let surface = data.data::<Mutex<Surface>>().unwrap().lock().unwrap();
+            let on_output = surface
+                .layer
+                .as_ref()
+                .is_some_and(|layer| layer.output.as_deref() == Some(name));
+            if on_output && layer_surface.is_alive() {
+                layer_surface.closed();
+            }
+        }
+
+        let global = self.output_globals[index].take().unwrap();
+        self.display.handle().remove_global::<State>(global);
+    }
+
+    /// Changes an output and announces its new state, like switching modes.
+    pub fn update_output(&mut self, name: &str, update: impl FnOnce(&mut OutputSpec)) {
+        let index = self.output_index(name);
+        update(&mut self.state.outputs[index]);
+
+        let spec = &self.state.outputs[index];
+        for output in self.state.bound_outputs.iter() {
+            if output.is_alive() && output.data::<usize>() == Some(&index) {
+                send_output_state(output, spec, false);
+            }
+        }
+    }
+
+    fn output_index(&self, name: &str) -> usize {
+        self.state
+            .outputs
+            .iter()
+            .position(|output| output.name == name)
+            .unwrap_or_else(|| panic!("Unknown output {}", name))
+    }
+
+    /// Handles requests until `condition` holds, failing the test if that takes too long.
+    pub fn run_until(&mut self, what: &str, condition: impl Fn(&State) -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !condition(&self.state) {
+            if Instant::now() > deadline {
+                panic!("Timed out waiting for {}: {:#?}", what, self.state.events);
+            }
+            self.dispatch(what);
+        }
+    }
+
+    /// Runs the `waypaper` client against the daemon while handling requests.
+    pub fn client(&mut self, args: &[&str]) -> std::process::Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_waypaper"))
+            .args(args)
+            .current_dir(self.dir.path())
+            .env_remove("WAYLAND_DISPLAY")
+            .env("XDG_RUNTIME_DIR", self.dir.path().join("runtime"))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let deadline = Instant::now() + TIMEOUT;
+        while child.try_wait().unwrap().is_none() {
+            if Instant::now() > deadline {
+                child.kill().ok();
+                panic!("Timed out waiting for waypaper {}", args.join(" "));
+            }
+            self.dispatch("the client");
+        }
+        child.wait_with_output().unwrap()
+    }
+
+    /// Handles requests until the daemon exits, failing the test if that takes too long.
+    pub fn wait_for_exit(&mut self) -> ExitStatus {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Some(status) = self.daemon.try_wait().unwrap() {
+                return status;
+            }
+            if Instant::now() > deadline {
+                panic!(
+                    "Timed out waiting for the daemon to exit:\n{}",
+                    self.daemon_log()
+                );
+            }
+            // The daemon may hang up at any point
+            self.display.dispatch_clients(&mut self.state).ok();
+            self.display.flush_clients().ok();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Everything the daemon logged so far.
+    pub fn daemon_log(&self) -> String {
+        std::fs::read_to_string(self.path("daemon.log")).unwrap_or_default()
+    }
+
+    /// Path of a file in the directory the daemon runs in.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn dispatch(&mut self, what: &str) {
+        self.display.dispatch_clients(&mut self.state).unwrap();
+        self.display.flush_clients().unwrap();
+
+        if let Some(status) = self.daemon.try_wait().unwrap() {
+            panic!(
+                "Daemon exited with {} while waiting for {}:\n{}",
+                status,
+                what,
+                self.daemon_log()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+impl Drop for Compositor {
+    fn drop(&mut self) {
+        self.daemon.kill().ok();
+        self.daemon.wait().ok();
+    }
+}
+
+struct NoClientData;
+
+impl ClientData for NoClientData {
+    fn initialized(&self, _: ClientId) {}
+    fn disconnected(&self, _: ClientId, _: DisconnectReason) {}
+}
+
+/// Per-surface state, kept as the user data of the `wl_surface`.
+#[derive(Default)]
+struct Surface {
+    pending: Option<WlBuffer>,
+    committed: Option<Committed>,
+    scale: i32,
+    transform: Option<wl_output::Transform>,
+    layer: Option<LayerRole>,
+}
+
+/// The buffer on screen, with its pixels at the time it was committed. Its memory is kept
+/// track of separately, since the client may destroy the `wl_buffer` while it is shown.
+struct Committed {
+    buffer: WlBuffer,
+    memory: Buffer,
+    pixels: Vec<u8>,
+}
+
+struct LayerRole {
+    resource: ZwlrLayerSurfaceV1,
+    output: Option<String>,
+    size: (u32, u32),
+    /// Whether the current size was configured, a new size is configured on the next commit.
+    configured: bool,
+}
+
+struct Pool {
+    file: File,
+}
+
+#[derive(Clone)]
+struct Buffer {
+    pool: Arc<Pool>,
+    offset: i32,
+    width: i32,
+    height: i32,
+    stride: i32,
+    format: wl_shm::Format,
+}
+
+impl Buffer {
+    fn read(&self) -> Vec<u8> {
+        let mut pixels = vec![0; (self.stride * self.height) as usize];
+        self.pool
+            .file
+            .read_exact_at(&mut pixels, self.offset as u64)
+            .unwrap();
+        pixels
+    }
+}
+
+impl GlobalDispatch<WlCompositor, ()> for State {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlCompositor>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<WlCompositor, ()> for State {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlCompositor,
+        request: wl_compositor::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_compositor::Request::CreateSurface { id } => {
+                data_init.init(
+                    id,
+                    Mutex::new(Surface {
+                        scale: 1,
+                        ..Default::default()
+                    }),
+                );
+            }
+            wl_compositor::Request::CreateRegion { id } => {
+                data_init.init(id, ());
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlRegion, ()> for State {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlRegion,
+        _: wl_region::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlSurface, Mutex<Surface>> for State {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        resource: &WlSurface,
+        request: wl_surface::Request,
+        data: &Mutex<Surface>,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        let mut surface = data.lock().unwrap();
+        match request {
+            wl_surface::Request::Attach { buffer, .. } => surface.pending = buffer,
+            wl_surface::Request::SetBufferScale { scale } => surface.scale = scale,
+            wl_surface::Request::SetBufferTransform {
+                transform: WEnum::Value(transform),
+            } => surface.transform = Some(transform),
+            wl_surface::Request::Commit => {
+                let surface_id = resource.id().protocol_id();
+                if let Some(buffer) = &surface.pending {
+                    // Like wlroots, buffers have to be a multiple of their scale
+                    let size = buffer.data::<Buffer>().unwrap();
+                    if size.width % surface.scale != 0 || size.height % surface.scale != 0 {
+                        resource.post_error(
+                            wl_surface::Error::InvalidSize,
+                            "buffer size not divisible by scale",
+                        );
+                        return;
+                    }
+                }
+                let frame = surface.pending.take().map(|buffer| {
+                    let frame = read_frame(&buffer);
+                    let committed = Committed {
+                        buffer: buffer.clone(),
+                        memory: buffer.data::<Buffer>().unwrap().clone(),
+                        pixels: frame.pixels.clone(),
+                    };
+                    if let Some(previous) = surface.committed.replace(committed) {
+                        if previous.memory.read() != previous.pixels {
+                            state.events.push(Event::Overwritten {
+                                buffer: previous.buffer.id().protocol_id(),
+                            });
+                        }
+                        // Like real compositors, give the previous buffer back once it is
+                        // replaced
+                        if previous.buffer != buffer && previous.buffer.is_alive() {
+                            previous.buffer.release();
+                        }
+                    }
+                    frame
+                });
+
+                let (output, requested_size) = match &mut surface.layer {
+                    Some(layer) => {
+                        if !layer.configured {
+                            layer.configured = true;
+                            let serial = state.next_serial;
+                            state.next_serial += 1;
+                            let (width, height) = layer.size;
+                            layer.resource.configure(serial, width, height);
+                            state.events.push(Event::Configure {
+                                surface: surface_id,
+                                serial,
+                            });
+                        }
+                        (layer.output.clone(), layer.size)
+                    }
+                    None => (None, (0, 0)),
+                };
+
+                state.events.push(Event::Commit(Commit {
+                    surface: surface_id,
+                    output,
+                    requested_size,
+                    scale: surface.scale,
+                    transform: surface.transform.unwrap_or(wl_output::Transform::Normal),
+                    frame,
+                }));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn read_frame(buffer: &WlBuffer) -> Frame {
+    let data = buffer.data::<Buffer>().unwrap();
+    let pixels = data.read();
+
+    Frame {
+        buffer: buffer.id().protocol_id(),
+        width: data.width,
+        height: data.height,
+        stride: data.stride,
+        format: data.format,
+        pixels,
+    }
+}
+
+impl GlobalDispatch<WlShm, ()> for State {
+    fn bind(
+        state: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlShm>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let shm = data_init.init(resource, ());
+        for format in state.formats.iter() {
+            shm.format(*format);
+        }
+    }
+}
+
+impl Dispatch<WlShm, ()> for State {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlShm,
+        request: wl_shm::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm::Request::CreatePool { id, fd, .. } = request {
+            data_init.init(
+                id,
+                Arc::new(Pool {
+                    file: File::from(fd),
+                }),
+            );
+        }
+    }
+}
+
+impl Dispatch<WlShmPool, Arc<Pool>> for State {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlShmPool,
+        request: wl_shm_pool::Request,
+        pool: &Arc<Pool>,
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm_pool::Request::CreateBuffer {
+            id,
+            offset,
+            width,
+            height,
+            stride,
+            format: WEnum::Value(format),
+        } = request
+        {
+            data_init.init(
+                id,
+                Buffer {
+                    pool: Arc::clone(pool),
+                    offset,
+                    width,
+                    height,
+                    stride,
+                    format,
+                },
+            );
+        }
+    }
+}
+
+impl Dispatch<WlBuffer, Buffer> for State {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlBuffer,
+        _: wl_buffer::Request,
+        _: &Buffer,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl GlobalDispatch<WlOutput, usize> for State {
+    fn bind(
+        state: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlOutput>,
+        index: &usize,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let output = data_init.init(resource, *index);
+        send_output_state(&output, &state.outputs[*index], true);
+        state.bound_outputs.push(output);
+    }
+}
+
+/// Sends everything about an output, followed by `done`. The name can only be sent once,
+/// right after binding.
+fn send_output_state(output: &WlOutput, spec: &OutputSpec, with_name: bool) {
+    output.geometry(
+        0,
+        0,
+        600,
+        340,
+        wl_output::Subpixel::Unknown,
+        spec.make.clone(),
+        spec.model.clone(),
+        spec.transform,
+    );
+    output.mode(
+        wl_output::Mode::Current | wl_output::Mode::Preferred,
+        spec.width,
+        spec.height,
+        60_000,
+    );
+    if output.version() >= 2 {
+        output.scale(spec.scale);
+    }
+    if output.version() >= 4 {
+        if with_name {
+            output.name(spec.name.clone());
+        }
+        output.description(spec.description.clone());
+    }
+    if output.version() >= 2 {
+        output.done();
+    }
+}
+
+impl Dispatch<WlOutput, usize> for State {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlOutput,
+        _: wl_output::Request,
+        _: &usize,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl GlobalDispatch<ZwlrLayerShellV1, ()> for State {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<ZwlrLayerShellV1>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<ZwlrLayerShellV1, ()> for State {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &ZwlrLayerShellV1,
+        request: zwlr_layer_shell_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwlr_layer_shell_v1::Request::GetLayerSurface {
+            id,
+            surface,
+            output,
+            ..
+        } = request
+        {
+            let output = output
+                .and_then(|output| output.data::<usize>().copied())
+                .map(|index| state.outputs[index].name.clone());
+            let resource = data_init.init(id, surface.clone());
+            state.layer_surfaces.push(resource.clone());
+
+            surface
+                .data::<Mutex<Surface>>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .layer = Some(LayerRole {
+                resource,
+                output,
+                size: (0, 0),
+                configured: false,
+            });
+        }
+    }
+}
+
+impl Dispatch<ZwlrLayerSurfaceV1, WlSurface> for State {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &ZwlrLayerSurfaceV1,
+        request: zwlr_layer_surface_v1::Request,
+        surface: &WlSurface,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        let data = surface.data::<Mutex<Surface>>().unwrap();
+        match request {
+            zwlr_layer_surface_v1::Request::SetSize { width, height } => {
+                if let Some(layer) = data.lock().unwrap().layer.as_mut() {
+                    if layer.size != (width, height) {
+                        layer.size = (width, height);
+                        layer.configured = false;
+                    }
+                }
+            }
+            zwlr_layer_surface_v1::Request::AckConfigure { serial } => {
+                state.events.push(Event::AckConfigure {
+                    surface: surface.id().protocol_id(),
+                    serial,
+                });
+            }
+            _ => {}
+        }
+    }
+}
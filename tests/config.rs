@@ -0,0 +1,23 @@
+//! Tests for the validation done by `waypaper check`.
+
+use waypaper::config::{self, Severity};
+
+#[test]
+fn out_of_range_tile_scales_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("waypaper.ini");
+    std::fs::write(
+        &path,
+        "[DP-1]\ntile_scale=1e30\n\n[DP-2]\ntile_scale=0.001\n\n[DP-3]\ntile_scale=2.5\n",
+    )
+    .unwrap();
+
+    let diagnostics = config::check(&path);
+    let lines: Vec<_> = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .map(|diagnostic| diagnostic.line)
+        .collect();
+    assert_eq!(lines, [Some(2), Some(5)], "{:?}", diagnostics);
+    assert!(diagnostics[0].message.contains("0.01 to 100"));
+}
@@ -0,0 +1,539 @@
+mod compositor;
+
+use compositor::{Commit, Compositor, Event, Globals, OutputSpec};
+use wayland_server::protocol::{wl_output::Transform, wl_shm::Format};
+
+/// The formats every compositor supports.
+const BASIC_FORMATS: [Format; 2] = [Format::Argb8888, Format::Xrgb8888];
+
+fn has_frames<'a>(outputs: &'a [&'a str]) -> impl Fn(&compositor::State) -> bool + 'a {
+    move |state| outputs.iter().all(|output| state.frame(output).is_some())
+}
+
+#[test]
+fn solid_colors_fill_each_output() {
+    let mut compositor = Compositor::start(
+        vec![
+            OutputSpec::new("DP-1", 64, 48),
+            OutputSpec::new("HDMI-A-1", 32, 32),
+        ],
+        BASIC_FORMATS.to_vec(),
+        "[DP-1]\ncolor=#336699\n\n[HDMI-A-1]\ncolor=#ff0000\n",
+    );
+    compositor.run_until("both outputs", has_frames(&["DP-1", "HDMI-A-1"]));
+
+    let frame = compositor.state.frame("DP-1").unwrap();
+    assert_eq!((frame.width, frame.height, frame.stride), (64, 48, 64 * 4));
+    assert_eq!(frame.format, Format::Xrgb8888);
+    assert!(frame
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0x99, 0x66, 0x33, 0xff]));
+
+    let frame = compositor.state.frame("HDMI-A-1").unwrap();
+    assert_eq!((frame.width, frame.height), (32, 32));
+    assert!(frame
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0x00, 0x00, 0xff, 0xff]));
+}
+
+#[test]
+fn packed_formats_are_preferred() {
+    let mut formats = BASIC_FORMATS.to_vec();
+    formats.push(Format::Bgr888);
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 30, 20)],
+        formats,
+        "[DP-1]\ncolor=#123456\n",
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+
+    let frame = compositor.state.frame("DP-1").unwrap();
+    assert_eq!(frame.format, Format::Bgr888);
+    assert_eq!(frame.stride, 30 * 3);
+    assert!(frame.pixels.chunks(3).all(|p| p == [0x12, 0x34, 0x56]));
+}
+
+#[test]
+fn configures_are_acked_before_buffers_are_attached() {
+    let mut compositor = Compositor::start(
+        vec![
+            OutputSpec::new("DP-1", 40, 30),
+            OutputSpec::new("DP-2", 20, 10),
+        ],
+        BASIC_FORMATS.to_vec(),
+        "",
+    );
+    compositor.run_until("both outputs", has_frames(&["DP-1", "DP-2"]));
+
+    let events = &compositor.state.events;
+    for (index, event) in events.iter().enumerate() {
+        let Event::Commit(Commit {
+            surface,
+            frame: Some(_),
+            ..
+        }) = event
+        else {
+            continue;
+        };
+
+        let configure = events[..index].iter().find_map(|event| match event {
+            Event::Configure {
+                surface: s, serial, ..
+            } if s == surface => Some(*serial),
+            _ => None,
+        });
+        let ack = events[..index].iter().find_map(|event| match event {
+            Event::AckConfigure {
+                surface: s, serial, ..
+            } if s == surface => Some(*serial),
+            _ => None,
+        });
+        assert!(configure.is_some(), "buffer attached before configure");
+        assert_eq!(ack, configure, "buffer attached before ack_configure");
+    }
+}
+
+#[test]
+fn scaled_and_rotated_outputs_get_hardware_sized_buffers() {
+    let mut rotated = OutputSpec::new("DP-1", 64, 32);
+    rotated.scale = 2;
+    rotated.transform = Transform::_90;
+    let mut compositor = Compositor::start(
+        vec![rotated],
+        BASIC_FORMATS.to_vec(),
+        "[DP-1]\ncolor=#000000\n",
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+
+    let commit = compositor
+        .state
+        .commits()
+        .filter(|commit| commit.frame.is_some())
+        .last()
+        .unwrap();
+    // The surface is sized in logical coordinates, after rotating and scaling
+    assert_eq!(commit.requested_size, (16, 32));
+    assert_eq!(commit.scale, 2);
+    assert_eq!(commit.transform, Transform::_90);
+
+    let frame = commit.frame.as_ref().unwrap();
+    assert_eq!((frame.width, frame.height), (64, 32));
+}
+
+#[test]
+fn modes_not_divisible_by_the_scale_are_drawn_unscaled() {
+    let mut output = OutputSpec::new("DP-1", 30, 20);
+    output.scale = 4;
+    let mut compositor = Compositor::start(
+        vec![output],
+        BASIC_FORMATS.to_vec(),
+        "[DP-1]\ncolor=#000000\n",
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+
+    let commit = compositor
+        .state
+        .commits()
+        .filter(|commit| commit.frame.is_some())
+        .last()
+        .unwrap();
+    // A 30x20 buffer at scale 4 is invalid, so the logical size is drawn at scale 1
+    assert_eq!(commit.requested_size, (7, 5));
+    assert_eq!(commit.scale, 1);
+
+    let frame = commit.frame.as_ref().unwrap();
+    assert_eq!((frame.width, frame.height), (7, 5));
+}
+
+#[test]
+fn images_are_drawn() {
+    let images = tempfile::tempdir().unwrap();
+    let image = images.path().join("green.png");
+    image::RgbImage::from_pixel(3, 3, image::Rgb([0, 200, 0]))
+        .save(&image)
+        .unwrap();
+
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 24, 16)],
+        BASIC_FORMATS.to_vec(),
+        &format!(
+            "[DP-1]\nbackground={}\nmode=fit\npad_color=#0000ff\n",
+            image.display()
+        ),
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+
+    // The square image is centered and the sides are padded
+    let frame = compositor.state.frame("DP-1").unwrap();
+    let center = frame.pixel(12, 8);
+    assert!(center[0] <= 1 && center[1].abs_diff(200) <= 1 && center[2] <= 1);
+    assert_eq!(frame.pixel(0, 8), [0xff, 0x00, 0x00, 0xff]);
+    assert_eq!(frame.pixel(23, 8), [0xff, 0x00, 0x00, 0xff]);
+}
+
+#[test]
+fn new_frames_go_into_the_other_buffer() {
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 16, 16)],
+        BASIC_FORMATS.to_vec(),
+        "[DP-1]\ncolor=#ffffff\n",
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+    let first = compositor.state.frame("DP-1").unwrap().clone();
+
+    std::fs::write(compositor.path("waypaper.ini"), "[DP-1]\ncolor=#000000\n").unwrap();
+    let output = compositor.client(&["reload"]);
+    assert!(output.status.success(), "{:?}", output);
+    compositor.run_until("a second frame", |state| state.frame_count("DP-1") >= 2);
+
+    let second = compositor.state.frame("DP-1").unwrap();
+    assert_ne!(second.buffer, first.buffer);
+    assert!(first
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0xff, 0xff, 0xff, 0xff]));
+    assert!(second
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0x00, 0x00, 0x00, 0xff]));
+}
+
+#[test]
+fn outputs_are_matched_by_description() {
+    let mut output = OutputSpec::new("DP-3", 8, 8);
+    output.description = "Dell Inc. DELL U2720Q 1234 (DP-3)".to_string();
+    let mut compositor = Compositor::start(
+        vec![output],
+        BASIC_FORMATS.to_vec(),
+        "[*]\ncolor=#ff0000\n\n[output \"Dell Inc. DELL U2720Q*\"]\ncolor=#00ff00\n",
+    );
+    compositor.run_until("a frame", has_frames(&["DP-3"]));
+
+    let frame = compositor.state.frame("DP-3").unwrap();
+    assert!(frame
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0x00, 0xff, 0x00, 0xff]));
+}
+
+#[test]
+fn broken_images_leave_other_outputs_drawn() {
+    // The header is intact, so the image only fails once it is decoded
+    let images = tempfile::tempdir().unwrap();
+    let image = images.path().join("broken.png");
+    let mut png = Vec::new();
+    image::RgbImage::from_pixel(64, 64, image::Rgb([1, 2, 3]))
+        .write_to(
+            &mut std::io::Cursor::new(&mut png),
+            image::ImageOutputFormat::Png,
+        )
+        .unwrap();
+    std::fs::write(&image, &png[..png.len() / 2]).unwrap();
+
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 8, 8), OutputSpec::new("DP-2", 8, 8)],
+        BASIC_FORMATS.to_vec(),
+        &format!(
+            "[DP-1]\nbackground={}\n\n[DP-2]\ncolor=#00ff00\n",
+            image.display()
+        ),
+    );
+    compositor.run_until("a frame", has_frames(&["DP-2"]));
+
+    let output = compositor.client(&["query"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(compositor.state.frame("DP-1").is_none());
+}
+
+#[test]
+fn slideshows_skip_images_that_fail_to_decode() {
+    let images = tempfile::tempdir().unwrap();
+    let mut png = Vec::new();
+    image::RgbImage::from_pixel(4, 4, image::Rgb([0, 200, 0]))
+        .write_to(
+            &mut std::io::Cursor::new(&mut png),
+            image::ImageOutputFormat::Png,
+        )
+        .unwrap();
+    // Sorted first, and only found to be broken once it is decoded
+    std::fs::write(images.path().join("a.png"), &png[..png.len() / 2]).unwrap();
+    std::fs::write(images.path().join("b.png"), &png).unwrap();
+    std::fs::write(images.path().join("notes.txt"), "not an image").unwrap();
+
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 8, 8)],
+        BASIC_FORMATS.to_vec(),
+        &format!("[DP-1]\nbackground={}\n", images.path().display()),
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+
+    let frame = compositor.state.frame("DP-1").unwrap();
+    assert!(frame
+        .pixels
+        .chunks(4)
+        .all(|p| p[0] <= 1 && p[1].abs_diff(200) <= 1 && p[2] <= 1));
+    let output = compositor.client(&["query", "DP-1"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("b.png"));
+}
+
+#[test]
+fn slideshows_keep_their_position_when_the_interval_changes() {
+    let images = tempfile::tempdir().unwrap();
+    for (name, color) in [("a.png", [0, 200, 0]), ("b.png", [200, 0, 0])] {
+        image::RgbImage::from_pixel(4, 4, image::Rgb(color))
+            .save(images.path().join(name))
+            .unwrap();
+    }
+    let config = |interval| {
+        format!(
+            "[DP-1]\nbackground={}\ninterval={}\n",
+            images.path().display(),
+            interval
+        )
+    };
+
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 8, 8)],
+        BASIC_FORMATS.to_vec(),
+        &config("1s"),
+    );
+    compositor.run_until("the second image", |state| {
+        state
+            .frame("DP-1")
+            .is_some_and(|frame| frame.pixel(0, 0)[2] > 100)
+    });
+    let frames = compositor.state.frame_count("DP-1");
+
+    std::fs::write(compositor.path("waypaper.ini"), config("1h")).unwrap();
+    let output = compositor.client(&["reload"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    // Restarting the slideshow would go back to the first image
+    let output = compositor.client(&["query", "DP-1"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("b.png"));
+    assert_eq!(compositor.state.frame_count("DP-1"), frames);
+}
+
+#[test]
+fn relative_patterns_are_set_as_slideshows() {
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 8, 8)],
+        BASIC_FORMATS.to_vec(),
+        "[DP-1]\ncolor=#000000\n",
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+
+    std::fs::create_dir(compositor.path("images")).unwrap();
+    image::RgbImage::from_pixel(4, 4, image::Rgb([0, 200, 0]))
+        .save(compositor.path("images/a.png"))
+        .unwrap();
+
+    // The client runs in the compositor's directory
+    let output = compositor.client(&["set", "DP-1", "images/*.png"]);
+    assert!(output.status.success(), "{:?}", output);
+    compositor.run_until("the image", |state| {
+        state.frame("DP-1").is_some_and(|frame| {
+            frame
+                .pixels
+                .chunks(4)
+                .all(|p| p[0] <= 1 && p[1].abs_diff(200) <= 1 && p[2] <= 1)
+        })
+    });
+}
+
+#[test]
+fn undecodable_images_are_rejected() {
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 8, 8)],
+        BASIC_FORMATS.to_vec(),
+        "[DP-1]\ncolor=#00ff00\n",
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+
+    std::fs::write(compositor.path("notes.png"), "not an image").unwrap();
+    let output = compositor.client(&["set", "DP-1", "notes.png"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("notes.png"));
+    assert_eq!(compositor.state.frame_count("DP-1"), 1);
+}
+
+#[test]
+fn missing_globals_are_reported() {
+    let mut compositor = Compositor::start_with(
+        Globals {
+            shm: None,
+            layer_shell: None,
+            ..Default::default()
+        },
+        vec![OutputSpec::new("DP-1", 8, 8)],
+        BASIC_FORMATS.to_vec(),
+        "",
+    );
+
+    let status = compositor.wait_for_exit();
+    let log = compositor.daemon_log();
+    assert_eq!(status.code(), Some(3), "{}", log);
+    assert!(log.contains("wl_shm, zwlr_layer_shell_v1"), "{}", log);
+    assert!(!log.contains("panicked"), "{}", log);
+}
+
+#[test]
+fn outdated_globals_are_reported() {
+    let mut compositor = Compositor::start_with(
+        Globals {
+            compositor: Some(3),
+            ..Default::default()
+        },
+        vec![OutputSpec::new("DP-1", 8, 8)],
+        BASIC_FORMATS.to_vec(),
+        "",
+    );
+
+    let status = compositor.wait_for_exit();
+    let log = compositor.daemon_log();
+    assert_eq!(status.code(), Some(3), "{}", log);
+    assert!(
+        log.contains("wl_compositor (version 3 is too old)"),
+        "{}",
+        log
+    );
+}
+
+#[test]
+fn outputs_without_names_are_drawn() {
+    let mut compositor = Compositor::start_with(
+        Globals {
+            output: 3,
+            ..Default::default()
+        },
+        vec![OutputSpec::new("DP-1", 8, 8), OutputSpec::new("DP-2", 4, 4)],
+        BASIC_FORMATS.to_vec(),
+        "[*]\ncolor=#00ff00\n",
+    );
+    compositor.run_until("both outputs", has_frames(&["DP-1", "DP-2"]));
+
+    let frame = compositor.state.frame("DP-2").unwrap();
+    assert_eq!((frame.width, frame.height), (4, 4));
+    assert!(frame
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0x00, 0xff, 0x00, 0xff]));
+}
+
+#[test]
+fn outputs_can_be_plugged_in_and_unplugged() {
+    let mut compositor = Compositor::start(
+        vec![
+            OutputSpec::new("DP-1", 16, 16),
+            OutputSpec::new("DP-2", 8, 8),
+        ],
+        BASIC_FORMATS.to_vec(),
+        "[*]\ncolor=#00ff00\n\n[DP-3]\ncolor=#0000ff\n",
+    );
+    compositor.run_until("both outputs", has_frames(&["DP-1", "DP-2"]));
+
+    compositor.remove_output("DP-2");
+    compositor.add_output(OutputSpec::new("DP-3", 24, 12));
+    compositor.run_until("the new output", has_frames(&["DP-3"]));
+
+    let output = compositor.client(&["list-outputs"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("DP-1") && stdout.contains("DP-3"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("DP-2"), "{}", stdout);
+
+    let frame = compositor.state.frame("DP-3").unwrap();
+    assert_eq!((frame.width, frame.height), (24, 12));
+    assert!(frame
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0xff, 0x00, 0x00, 0xff]));
+    let frame = compositor.state.frame("DP-1").unwrap();
+    assert!(frame
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0x00, 0xff, 0x00, 0xff]));
+}
+
+#[test]
+fn mode_and_scale_changes_resize_the_surface() {
+    let mut compositor = Compositor::start(
+        vec![OutputSpec::new("DP-1", 64, 48)],
+        BASIC_FORMATS.to_vec(),
+        "[DP-1]\ncolor=#336699\n",
+    );
+    compositor.run_until("a frame", has_frames(&["DP-1"]));
+
+    compositor.update_output("DP-1", |output| {
+        output.width = 32;
+        output.height = 40;
+        output.scale = 2;
+    });
+    compositor.run_until("a frame in the new mode", |state| {
+        state
+            .frame("DP-1")
+            .is_some_and(|frame| (frame.width, frame.height) == (32, 40))
+    });
+
+    let commit = compositor
+        .state
+        .commits()
+        .filter(|commit| commit.frame.is_some())
+        .last()
+        .unwrap();
+    assert_eq!(commit.requested_size, (16, 20));
+    assert_eq!(commit.scale, 2);
+    assert!(commit
+        .frame
+        .as_ref()
+        .unwrap()
+        .pixels
+        .chunks(4)
+        .all(|p| p == [0x99, 0x66, 0x33, 0xff]));
+}
+
+#[test]
+fn buffers_on_screen_are_not_drawn_into_when_outputs_change() {
+    // The gradient changes with the width, so drawing into a shown buffer can't go unnoticed
+    let mut compositor = Compositor::start(
+        vec![
+            OutputSpec::new("DP-1", 16, 16),
+            OutputSpec::new("DP-2", 8, 8),
+        ],
+        BASIC_FORMATS.to_vec(),
+        "[DP-1]\ncolor=#ffffff\n\n[DP-2]\ngradient=#000000,#ffffff,0\n",
+    );
+    compositor.run_until("both outputs", has_frames(&["DP-1", "DP-2"]));
+
+    compositor.update_output("DP-1", |output| output.scale = 2);
+    compositor.run_until("a frame with the new scale", |state| {
+        state
+            .commits()
+            .any(|commit| commit.scale == 2 && commit.frame.is_some())
+    });
+    compositor.update_output("DP-2", |output| output.width = 12);
+    compositor.run_until("a frame in the new mode", |state| {
+        state.frame("DP-2").is_some_and(|frame| frame.width == 12)
+    });
+    compositor.remove_output("DP-1");
+    compositor.add_output(OutputSpec::new("DP-3", 16, 16));
+    compositor.run_until("the new output", has_frames(&["DP-3"]));
+
+    std::fs::write(compositor.path("waypaper.ini"), "[*]\ncolor=#0000ff\n").unwrap();
+    let output = compositor.client(&["reload"]);
+    assert!(output.status.success(), "{:?}", output);
+    compositor.run_until("the new color", |state| {
+        ["DP-2", "DP-3"].iter().all(|output| {
+            state
+                .frame(output)
+                .is_some_and(|frame| frame.pixel(0, 0) == [0xff, 0x00, 0x00, 0xff])
+        })
+    });
+
+    assert_eq!(compositor.state.overwritten(), Vec::<u32>::new());
+}
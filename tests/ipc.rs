@@ -0,0 +1,20 @@
+//! Tests for `waypaper::ipc`.
+
+use std::path::PathBuf;
+
+use waypaper::ipc;
+
+// Both cases share a test since they change the environment of the whole process
+#[test]
+fn socket_path() {
+    std::env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
+    std::env::set_var("WAYLAND_DISPLAY", "/run/user/1000/wayland-1");
+    assert_eq!(
+        ipc::socket_path(),
+        Some(PathBuf::from("/run/user/1000/waypaper-wayland-1.sock"))
+    );
+
+    // Shared directories like /tmp are not used
+    std::env::remove_var("XDG_RUNTIME_DIR");
+    assert_eq!(ipc::socket_path(), None);
+}
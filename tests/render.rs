@@ -0,0 +1,214 @@
+//! Tests for `waypaper::render`. Every wallpaper mode is compared against the reference
+//! images in `tests/golden`.
+//!
+//! Run with `UPDATE_GOLDEN=1` to rewrite the references after an intended change, then check
+//! the new images by eye before committing them.
+
+use std::path::PathBuf;
+
+use image::{DynamicImage, Rgba, RgbaImage};
+use wayland_client::protocol::{wl_output::Transform, wl_shm::Format};
+use waypaper::{
+    config::{Color, Mode, OutputPreferences},
+    render::{self, Geometry, Renderer},
+};
+
+/// Largest difference allowed per channel, so rounding changes in the resize filter pass
+/// while shifted or cropped images don't.
+const TOLERANCE: u8 = 2;
+
+/// Landscape, portrait and a source larger than every target.
+const SOURCE_SIZES: [(u32, u32); 3] = [(7, 5), (5, 9), (40, 30)];
+
+/// Wider, taller and odd-sized outputs.
+const TARGET_SIZES: [(u32, u32); 3] = [(16, 9), (9, 16), (13, 7)];
+
+const PAD_COLOR: Color = Color {
+    r: 0x20,
+    g: 0x40,
+    b: 0x60,
+};
+
+/// A gradient with a white border, so scaling, shifting and cropping all show up.
+fn source(width: u32, height: u32) -> DynamicImage {
+    let image = RgbaImage::from_fn(width, height, |x, y| {
+        if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+            return Rgba([0xff, 0xff, 0xff, 0xff]);
+        }
+        let r = (x * 255 / (width - 1)) as u8;
+        let g = (y * 255 / (height - 1)) as u8;
+        Rgba([r, g, 0x80, 0xff])
+    });
+    DynamicImage::ImageRgba8(image)
+}
+
+fn prefs(mode: Mode) -> OutputPreferences {
+    OutputPreferences {
+        mode,
+        pad_color: PAD_COLOR,
+        ..Default::default()
+    }
+}
+
+fn assert_matches_golden(name: &str, image: &DynamicImage) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.png", name));
+    let actual = image.to_rgba8();
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        actual.save(&path).unwrap();
+        return;
+    }
+
+    let expected = image::open(&path)
+        .unwrap_or_else(|e| panic!("Error reading {}: {}", path.display(), e))
+        .to_rgba8();
+    assert_close(name, &expected, &actual);
+}
+
+/// Compares two images pixel by pixel, allowing for [`TOLERANCE`].
+fn assert_close(name: &str, expected: &RgbaImage, actual: &RgbaImage) {
+    assert_eq!(
+        expected.dimensions(),
+        actual.dimensions(),
+        "{}: wrong size",
+        name
+    );
+    for (x, y, expected) in expected.enumerate_pixels() {
+        let actual = actual.get_pixel(x, y);
+        let difference = expected
+            .0
+            .iter()
+            .zip(actual.0)
+            .map(|(expected, actual)| expected.abs_diff(actual))
+            .max()
+            .unwrap();
+        assert!(
+            difference <= TOLERANCE,
+            "{}: pixel ({}, {}) is {:?}, expected {:?}",
+            name,
+            x,
+            y,
+            actual.0,
+            expected.0
+        );
+    }
+}
+
+fn check_mode(mode: Mode) {
+    for (source_width, source_height) in SOURCE_SIZES {
+        for (width, height) in TARGET_SIZES {
+            let image = render::apply_image_mode(
+                source(source_width, source_height),
+                &prefs(mode),
+                width,
+                height,
+            );
+            let name = format!(
+                "{}-{}x{}-on-{}x{}",
+                mode, source_width, source_height, width, height
+            );
+            assert_matches_golden(&name, &image);
+        }
+    }
+}
+
+#[test]
+fn fill() {
+    check_mode(Mode::Fill);
+}
+
+#[test]
+fn fit() {
+    check_mode(Mode::Fit);
+}
+
+#[test]
+fn stretch() {
+    check_mode(Mode::Stretch);
+}
+
+#[test]
+fn center() {
+    check_mode(Mode::Center);
+}
+
+#[test]
+fn tile() {
+    check_mode(Mode::Tile);
+}
+
+#[test]
+fn tile_with_scale_and_offset() {
+    let prefs = OutputPreferences {
+        tile_scale: Some(1.5),
+        tile_offset: (-4, 3),
+        ..prefs(Mode::Tile)
+    };
+    let image = render::apply_image_mode(source(7, 5), &prefs, 13, 7);
+    assert_matches_golden("tile-7x5-scaled-offset-on-13x7", &image);
+}
+
+#[test]
+fn center_copies_pixels_unscaled() {
+    let image = render::apply_image_mode(source(5, 9), &prefs(Mode::Center), 9, 16).to_rgba8();
+    let source = source(5, 9).to_rgba8();
+
+    // (9 - 5) / 2 = 2 columns and (16 - 9) / 2 = 3 rows of padding before the image
+    for (x, y, pixel) in source.enumerate_pixels() {
+        assert_eq!(image.get_pixel(x + 2, y + 3), pixel);
+    }
+    let pad = Rgba([PAD_COLOR.r, PAD_COLOR.g, PAD_COLOR.b, 0xff]);
+    assert_eq!(*image.get_pixel(1, 3), pad);
+    assert_eq!(*image.get_pixel(2, 12), pad);
+}
+
+#[test]
+fn frames_are_laid_out_for_the_buffer_transform() {
+    // A 4x2 panel rotated to portrait shows a 2x4 image
+    let geometry = Geometry {
+        width: 4,
+        height: 2,
+        transform: Transform::_90,
+    };
+    let renderer = Renderer::new(geometry, Format::Bgr888);
+    let image = RgbaImage::from_fn(2, 4, |_, y| match y {
+        0 => Rgba([0xff, 0x00, 0x00, 0xff]),
+        _ => Rgba([0x00, 0x00, 0xff, 0xff]),
+    });
+
+    let frame = renderer.render_image(DynamicImage::ImageRgba8(image), &prefs(Mode::Center));
+    assert_eq!(frame.len(), renderer.frame_len());
+
+    // The top row of the image ends up in the first column of the buffer
+    for (index, pixel) in frame.chunks(3).enumerate() {
+        let expected = match index % 4 {
+            0 => [0xff, 0x00, 0x00],
+            _ => [0x00, 0x00, 0xff],
+        };
+        assert_eq!(pixel, expected, "pixel {}", index);
+    }
+}
+
+#[test]
+fn tiles_larger_than_the_output_are_cropped() {
+    let prefs = OutputPreferences {
+        tile_scale: Some(3.0),
+        tile_offset: (-4, 3),
+        ..prefs(Mode::Tile)
+    };
+    let image = render::apply_image_mode(source(7, 5), &prefs, 13, 7);
+
+    // The visible part of the whole 21x15 tile, which wraps around vertically
+    let tile = source(7, 5)
+        .resize_exact(21, 15, render::RESIZE_FILTER)
+        .to_rgba8();
+    let expected = RgbaImage::from_fn(13, 7, |x, y| {
+        *tile.get_pixel(
+            (x as i32 + 4) as u32 % 21,
+            (y as i32 - 3).rem_euclid(15) as u32,
+        )
+    });
+    assert_close("tile-7x5-cropped-on-13x7", &expected, &image.to_rgba8());
+}